@@ -3,16 +3,29 @@ use axum::response::IntoResponse;
 use axum::http;
 use serde_json::json;
 
+use super::LoadProgress;
+
+/// Seconds a client is asked to wait before retrying an unavailable project.
+const RETRY_AFTER_SECS: &str = "5";
+
 #[derive(Debug)]
 pub enum AppError {
     InternalError(String),
     Teapot(String),
     BadRequest(String),
+    NotFound(String),
+    /// The target exists already, e.g. an image name taken.
+    Conflict(String),
+    PayloadTooLarge(String),
+    /// The target is not ready yet, e.g. a project still loading.
+    ServiceUnavailable(String, Option<LoadProgress>),
 }
 
 #[derive(serde::Serialize, Debug)]
 pub struct AppErrorPayload {
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    progress: Option<LoadProgress>,
 }
 
 impl IntoResponse for AppError {
@@ -21,6 +34,7 @@ impl IntoResponse for AppError {
             AppError::InternalError(msg) => {
                 let body = json!( AppErrorPayload{
                     message: msg,
+                    progress: None,
                 });
 
                 (   
@@ -33,6 +47,7 @@ impl IntoResponse for AppError {
             AppError::Teapot(msg) => {
                 let body = json!( AppErrorPayload{
                     message: msg,
+                    progress: None,
                 });
 
                 (   
@@ -45,6 +60,7 @@ impl IntoResponse for AppError {
             AppError::BadRequest(msg) => {
                 let body = json!( AppErrorPayload{
                     message: msg,
+                    progress: None,
                 });
 
                 (   
@@ -53,6 +69,61 @@ impl IntoResponse for AppError {
                     body.to_string()
                 ).into_response()
             },
+
+            AppError::NotFound(msg) => {
+                let body = json!( AppErrorPayload{
+                    message: msg,
+                    progress: None,
+                });
+
+                (   
+                    http::StatusCode::NOT_FOUND, 
+                    [(http::header::CONTENT_TYPE, "application/json")],
+                    body.to_string()
+                ).into_response()
+            },
+
+            AppError::Conflict(msg) => {
+                let body = json!( AppErrorPayload{
+                    message: msg,
+                    progress: None,
+                });
+
+                (   
+                    http::StatusCode::CONFLICT, 
+                    [(http::header::CONTENT_TYPE, "application/json")],
+                    body.to_string()
+                ).into_response()
+            },
+
+            AppError::PayloadTooLarge(msg) => {
+                let body = json!( AppErrorPayload{
+                    message: msg,
+                    progress: None,
+                });
+
+                (   
+                    http::StatusCode::PAYLOAD_TOO_LARGE, 
+                    [(http::header::CONTENT_TYPE, "application/json")],
+                    body.to_string()
+                ).into_response()
+            },
+
+            AppError::ServiceUnavailable(msg, progress) => {
+                let body = json!( AppErrorPayload{
+                    message: msg,
+                    progress,
+                });
+
+                (   
+                    http::StatusCode::SERVICE_UNAVAILABLE, 
+                    [
+                        (http::header::CONTENT_TYPE, "application/json"),
+                        (http::header::RETRY_AFTER, RETRY_AFTER_SECS),
+                    ],
+                    body.to_string()
+                ).into_response()
+            },
         }
     }
 }
\ No newline at end of file
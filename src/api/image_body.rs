@@ -0,0 +1,369 @@
+//! Requests carrying images, in any of the accepted encodings:
+//!
+//! - `application/json`, with the image as a base64 string in `data`,
+//! - `multipart/form-data`, with the image as a file part and the other
+//!   fields as text parts,
+//! - `application/octet-stream` (or any `image/*`), with the raw image as
+//!   the body and the other fields in the query string.
+//!
+//! Batch requests take JSON, or a multipart form with one file part per
+//! image.
+//!
+//! Image bytes are read chunk by chunk, and the request is refused as soon
+//! as it goes over the body limit.
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use axum::body::Bytes;
+use axum::extract::{FromRef, FromRequest, Multipart, Query, Request};
+use axum::extract::multipart::MultipartError;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use image::DynamicImage;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use super::{
+    AppError, BatchImage, CompareBatchReq, CompareImageReq, ComparePairReq, CompareQuery, PairImage,
+    UploadBatchReq, UploadImageReq,
+};
+use crate::HasSingleImage;
+
+/// Name of the multipart part holding the image, when it has no file name.
+const IMAGE_FIELD: &str = "image";
+
+/// Largest accepted request body, in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimit(pub usize);
+
+/// Requests which can also be sent as form fields or query string.
+pub trait FormRequest: DeserializeOwned + HasSingleImage + Send {
+    /// Fields taken as text. Other fields are parsed as JSON values when
+    /// they can be, e.g. `top_k=5` or `weights={"PHASH":2}`.
+    const TEXT_FIELDS: &'static [&'static str];
+
+    /// Field which takes the file name of the uploaded image, if not given.
+    const FILE_NAME_FIELD: Option<&'static str> = None;
+}
+
+impl FormRequest for CompareImageReq {
+    const TEXT_FIELDS: &'static [&'static str] = &["project_name", "data"];
+}
+
+impl FormRequest for UploadImageReq {
+    const TEXT_FIELDS: &'static [&'static str] = &["project_name", "image_name", "data"];
+    const FILE_NAME_FIELD: Option<&'static str> = Some("image_name");
+}
+
+/// Batch requests, with their images under `images`, which can also be sent
+/// as a multipart form.
+pub trait BatchFormRequest: DeserializeOwned + Send {
+    type Item: HasSingleImage;
+
+    /// Fields taken as text, see `FormRequest::TEXT_FIELDS`.
+    const TEXT_FIELDS: &'static [&'static str];
+
+    /// Field of an item which takes the file name of its image part.
+    const ITEM_NAME_FIELD: &'static str;
+
+    fn items(&self) -> &[Self::Item];
+}
+
+impl BatchFormRequest for UploadBatchReq {
+    type Item = BatchImage;
+    const TEXT_FIELDS: &'static [&'static str] = &[];
+    const ITEM_NAME_FIELD: &'static str = "image_name";
+
+    fn items(&self) -> &[BatchImage] {
+        &self.images
+    }
+}
+
+impl BatchFormRequest for CompareBatchReq {
+    type Item = CompareQuery;
+    const TEXT_FIELDS: &'static [&'static str] = &["project_name"];
+    const ITEM_NAME_FIELD: &'static str = "image_name";
+
+    fn items(&self) -> &[CompareQuery] {
+        &self.images
+    }
+}
+
+impl BatchFormRequest for ComparePairReq {
+    type Item = PairImage;
+    const TEXT_FIELDS: &'static [&'static str] = &[];
+    const ITEM_NAME_FIELD: &'static str = "image_name";
+
+    fn items(&self) -> &[PairImage] {
+        &self.images
+    }
+}
+
+/// A request, and the raw bytes of its image if it was not sent as base64.
+#[derive(Debug)]
+pub struct ImageRequest<T> {
+    pub request: T,
+    image_bytes: Option<Bytes>,
+}
+
+impl<T: FormRequest> ImageRequest<T> {
+    /// Decode the image of the request, whichever way it was sent.
+    pub fn get_image(&self) -> Result<DynamicImage, Box<dyn Error>> {
+        match &self.image_bytes {
+            Some(bytes) => Ok(image::load_from_memory(bytes)?),
+            None => self.request.get_image(),
+        }
+    }
+}
+
+/// A batch request, and the raw bytes of its images sent as file parts.
+#[derive(Debug)]
+pub struct ImageBatchRequest<T> {
+    pub request: T,
+    image_bytes: Vec<Option<Bytes>>,
+}
+
+impl<T: BatchFormRequest> ImageBatchRequest<T> {
+    /// Number of images in the batch.
+    pub fn len(&self) -> usize {
+        self.request.items().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.request.items().is_empty()
+    }
+
+    /// Decode the `i`-th image of the batch, whichever way it was sent.
+    pub fn get_image(&self, i: usize) -> Result<DynamicImage, Box<dyn Error>> {
+        match self.image_bytes.get(i) {
+            Some(Some(bytes)) => Ok(image::load_from_memory(bytes)?),
+            _ => self.request.items().get(i).ok_or("no such image in batch")?.get_image(),
+        }
+    }
+}
+
+fn too_large(limit: usize) -> Response {
+    AppError::PayloadTooLarge(format!("request body is larger than {} bytes", limit)).into_response()
+}
+
+fn multipart_error(limit: usize) -> impl Fn(MultipartError) -> Response {
+    move |e| match e.status() {
+        StatusCode::PAYLOAD_TOO_LARGE => too_large(limit),
+        _ => AppError::BadRequest(e.body_text()).into_response(),
+    }
+}
+
+/// Turn text fields into a JSON object, fields out of `text_fields` are
+/// parsed as JSON values when they can be.
+fn object_from_fields(fields: HashMap<String, String>, text_fields: &[&str]) -> serde_json::Map<String, Value> {
+    fields.into_iter()
+        .map(|(name, value)| {
+            let value = match text_fields.contains(&name.as_str()) {
+                true => Value::String(value),
+                false => serde_json::from_str(&value).unwrap_or(Value::String(value)),
+            };
+            (name, value)
+        })
+        .collect()
+}
+
+fn request_from_object<T: DeserializeOwned>(object: serde_json::Map<String, Value>) -> Result<T, AppError> {
+    serde_json::from_value(Value::Object(object))
+        .map_err(|e| AppError::BadRequest(format!("invalid request fields: {}", e)))
+}
+
+/// Build a request from text fields, see `FormRequest::TEXT_FIELDS`.
+///
+/// The image is sent aside, so `data` may be left out.
+fn request_from_fields<T: FormRequest>(fields: HashMap<String, String>) -> Result<T, AppError> {
+    let mut object = object_from_fields(fields, T::TEXT_FIELDS);
+    object.entry("data").or_insert_with(|| Value::String(String::new()));
+    request_from_object(object)
+}
+
+/// Read a file part, counting its bytes into `total` against the limit.
+async fn read_file_part(field: &mut axum::extract::multipart::Field<'_>, total: &mut usize, limit: usize)
+    -> Result<Bytes, Response> {
+
+    let mut data: Vec<u8> = vec![];
+    while let Some(chunk) = field.chunk().await.map_err(multipart_error(limit))? {
+        *total += chunk.len();
+        if *total > limit {
+            return Err(too_large(limit));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(data))
+}
+
+async fn from_multipart<S, T>(req: Request, state: &S, limit: usize) -> Result<ImageRequest<T>, Response>
+where
+    S: Send + Sync,
+    T: FormRequest,
+{
+    let mut multipart = Multipart::from_request(req, state).await.map_err(IntoResponse::into_response)?;
+
+    let mut fields: HashMap<String, String> = HashMap::new();
+    let mut image_bytes: Option<Bytes> = None;
+    let mut file_name: Option<String> = None;
+
+    while let Some(mut field) = multipart.next_field().await.map_err(multipart_error(limit))? {
+        let name = field.name().unwrap_or_default().to_owned();
+
+        if field.file_name().is_none() && name != IMAGE_FIELD {
+            let value = field.text().await.map_err(multipart_error(limit))?;
+            fields.insert(name, value);
+            continue;
+        }
+
+        if image_bytes.is_some() {
+            return Err(AppError::BadRequest("only one image per request".into()).into_response());
+        }
+        file_name = field.file_name().map(str::to_owned);
+        image_bytes = Some(read_file_part(&mut field, &mut 0, limit).await?);
+    }
+
+    if let (Some(field), Some(file_name)) = (T::FILE_NAME_FIELD, file_name) {
+        fields.entry(field.to_owned()).or_insert(file_name);
+    }
+
+    let request = request_from_fields(fields).map_err(IntoResponse::into_response)?;
+    Ok(ImageRequest { request, image_bytes })
+}
+
+async fn from_raw_body<T: FormRequest>(req: Request, limit: usize) -> Result<ImageRequest<T>, Response> {
+    let Query(fields) = Query::<HashMap<String, String>>::try_from_uri(req.uri())
+        .map_err(IntoResponse::into_response)?;
+    let request = request_from_fields(fields).map_err(IntoResponse::into_response)?;
+
+    let content_length = req.headers().get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > limit) {
+        return Err(too_large(limit));
+    }
+
+    let image_bytes = Limited::new(req.into_body(), limit).collect().await
+        .map_err(|e| match e.downcast_ref::<LengthLimitError>() {
+            Some(_) => too_large(limit),
+            None => AppError::BadRequest(format!("cannot read request body: {}", e)).into_response(),
+        })?
+        .to_bytes();
+
+    Ok(ImageRequest { request, image_bytes: Some(image_bytes) })
+}
+
+impl<S, T> FromRequest<S> for ImageRequest<T>
+where
+    S: Send + Sync,
+    T: FormRequest,
+    BodyLimit: FromRef<S>,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let BodyLimit(limit) = BodyLimit::from_ref(state);
+
+        let content_type = req.headers().get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        if content_type.starts_with("multipart/form-data") {
+            from_multipart(req, state, limit).await
+        } else if content_type.starts_with("application/octet-stream") || content_type.starts_with("image/") {
+            from_raw_body(req, limit).await
+        } else {
+            // JSON, and anything else is refused the way JSON requests are.
+            let Json(request) = Json::<T>::from_request(req, state).await.map_err(IntoResponse::into_response)?;
+            Ok(ImageRequest { request, image_bytes: None })
+        }
+    }
+}
+
+impl<S, T> FromRequest<S> for ImageBatchRequest<T>
+where
+    S: Send + Sync,
+    T: BatchFormRequest,
+    BodyLimit: FromRef<S>,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let BodyLimit(limit) = BodyLimit::from_ref(state);
+
+        let is_multipart = req.headers().get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.to_ascii_lowercase().starts_with("multipart/form-data"));
+
+        if !is_multipart {
+            let Json(request) = Json::<T>::from_request(req, state).await.map_err(IntoResponse::into_response)?;
+            return Ok(ImageBatchRequest { request, image_bytes: vec![] });
+        }
+
+        let mut multipart = Multipart::from_request(req, state).await.map_err(IntoResponse::into_response)?;
+
+        let mut fields: HashMap<String, String> = HashMap::new();
+        let mut items: Vec<Value> = vec![];
+        let mut image_bytes: Vec<Option<Bytes>> = vec![];
+        let mut total = 0;
+
+        while let Some(mut field) = multipart.next_field().await.map_err(multipart_error(limit))? {
+            let name = field.name().unwrap_or_default().to_owned();
+
+            let Some(file_name) = field.file_name().map(str::to_owned) else {
+                let value = field.text().await.map_err(multipart_error(limit))?;
+                fields.insert(name, value);
+                continue;
+            };
+
+            let mut item = serde_json::Map::new();
+            item.insert(T::ITEM_NAME_FIELD.to_owned(), Value::String(file_name));
+            item.insert("data".to_owned(), Value::String(String::new()));
+            items.push(Value::Object(item));
+            image_bytes.push(Some(read_file_part(&mut field, &mut total, limit).await?));
+        }
+
+        let mut object = object_from_fields(fields, T::TEXT_FIELDS);
+        object.insert("images".to_owned(), Value::Array(items));
+        let request = request_from_object(object).map_err(IntoResponse::into_response)?;
+
+        Ok(ImageBatchRequest { request, image_bytes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_from_fields() {
+        let fields = HashMap::from([
+            ("project_name".to_owned(), "2023".to_owned()),
+            ("top_k".to_owned(), "5".to_owned()),
+            ("weights".to_owned(), r#"{"PHASH": 2, "DHASH": 1}"#.to_owned()),
+            ("with_image".to_owned(), "true".to_owned()),
+        ]);
+        let req: CompareImageReq = request_from_fields(fields).unwrap();
+        assert_eq!(req.project_name, "2023");
+        assert_eq!(req.top_k, Some(5));
+        assert_eq!(req.weights.unwrap().len(), 2);
+        assert!(req.with_image);
+        assert!(req.data.is_empty());
+
+        // plain words are taken as text when they are not JSON.
+        let fields = HashMap::from([
+            ("project_name".to_owned(), "scans".to_owned()),
+            ("image_name".to_owned(), "1.png".to_owned()),
+            ("hash_type".to_owned(), "DHASH".to_owned()),
+        ]);
+        let req: UploadImageReq = request_from_fields(fields).unwrap();
+        assert_eq!(req.hash_type, Some(crate::image_hash::HashType::DHASH));
+
+        let fields = HashMap::from([("top_k".to_owned(), "many".to_owned())]);
+        assert!(request_from_fields::<CompareImageReq>(fields).is_err());
+    }
+}
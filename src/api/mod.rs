@@ -1,19 +1,54 @@
 mod api_error;
+mod image_body;
 pub use api_error::*;
+pub use image_body::{BatchFormRequest, BodyLimit, FormRequest, ImageBatchRequest, ImageRequest};
 
 use serde::{Deserialize, Serialize};
 
+use std::collections::{BTreeMap, HashMap};
+
+use crate::image_hash::{HashEncoding, HashType, HasherConfig};
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct SimilarImageEntry {
 	pub image_name: String,	  // the name of image
+	#[serde(default)]
+	pub project_name: String, // the project the image belongs to
 	pub distance: f32,		  // distance score, lower is closer
+	#[serde(default)]
+	pub similarity: f32,	  // similarity score in 0..1, higher is closer
 	pub data: Option<String>, // image data as base64 string.
 }
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct CompareImageReq {
-	pub project_name: String,
-	pub data: String,
+	#[serde(default)]
+	pub project_name: String, // project to search in, `*` for every project.
+	#[serde(default)]
+	pub project_names: Option<Vec<String>>, // projects to search in, instead of `project_name`.
+	#[serde(default)]
+	pub data: String, // base64 encoded image, unless sent otherwise or `query_hash` is given.
+	#[serde(default)]
+	pub query_hash: Option<QueryHash>, // hashes computed by the client, searched instead of an image.
+	#[serde(default)]
     pub with_image: bool,
+	#[serde(default)]
+	pub weights: Option<HashMap<HashType, f32>>, // weight per hash type for fused scoring, equal weights if omitted.
+	#[serde(default)]
+	pub top_k: Option<usize>, // number of closest images to return.
+	#[serde(default)]
+	pub max_distance: Option<f32>, // only images with distance under this.
+	#[serde(default)]
+	pub max_normalized_distance: Option<f32>, // only images with normalized distance (0..1) under this.
+}
+
+/// Hashes of a query image computed by the client.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct QueryHash {
+	pub hashes: HashMap<HashType, String>, // encoded hash per hash type.
+	#[serde(default)]
+	pub encoding: HashEncoding, // `hex` or `base64` of the bits, most significant first.
+	#[serde(default)]
+	pub hasher_config: Option<HasherConfig>, // config the hashes were computed with, defaults if omitted.
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -21,32 +56,224 @@ pub struct CompareImageResp {
 	pub success: bool,
 	pub message: String,
 	pub project_name: String, // the name of project
+	#[serde(default)]
+	pub projects: Vec<String>, // projects searched.
+	pub compare_result: Vec<SimilarImageEntry>,
+}
+
+/// One query image of a batch compare.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct CompareQuery {
+	pub image_name: String, // name of the query, to tell results apart.
+	#[serde(default)]
+	pub data: String, // base64 encoded image, unless sent as a file part.
+	#[serde(default)]
+	pub top_k: Option<usize>, // overrides the batch `top_k` for this query.
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CompareBatchReq {
+	pub project_name: String,
+	pub images: Vec<CompareQuery>,
+	#[serde(default)]
+    pub with_image: bool,
+	#[serde(default)]
+	pub weights: Option<HashMap<HashType, f32>>, // weight per hash type for fused scoring, equal weights if omitted.
+	#[serde(default)]
+	pub top_k: Option<usize>, // number of closest images to return per query.
+	#[serde(default)]
+	pub max_distance: Option<f32>, // only images with distance under this.
+	#[serde(default)]
+	pub max_normalized_distance: Option<f32>, // only images with normalized distance (0..1) under this.
+}
+
+/// Outcome of one query of a batch compare.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CompareBatchItem {
+	pub image_name: String,
+	pub success: bool,
+	pub message: String,
 	pub compare_result: Vec<SimilarImageEntry>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CompareBatchResp {
+	pub success: bool,
+	pub message: String,
+	pub project_name: String,
+	pub results: Vec<CompareBatchItem>,
+}
+
+/// One of the two images of a pair compare.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PairImage {
+	#[serde(default)]
+	pub image_name: String, // optional label.
+	#[serde(default)]
+	pub data: String, // base64 encoded image, unless sent as a file part.
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ComparePairReq {
+	pub images: Vec<PairImage>, // exactly two images.
+	#[serde(default)]
+	pub hasher_config: Option<HasherConfig>, // hash / resize sizes and filter, defaults if omitted.
+}
+
+/// Distance of the two images of a pair compare on one hash type.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PairDistance {
+	pub hash_type: HashType,
+	pub distance: f32, // hamming distance, lower is closer
+	pub normalized_distance: f32, // distance divided by the hash bit length
+	pub similarity: f32, // similarity score in 0..1, higher is closer
+}
+
+/// A hash type a pair compare does not measure, as the hasher config does
+/// not suit it.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SkippedHashType {
+	pub hash_type: HashType,
+	pub reason: String, // why the hasher config does not suit it.
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ComparePairResp {
+	pub success: bool,
+	pub message: String,
+	pub image_names: Vec<String>,
+	pub hasher_config: HasherConfig,
+	pub distances: Vec<PairDistance>, // one per measured hash type.
+	#[serde(default)]
+	pub skipped: Vec<SkippedHashType>, // hash types which are not measured.
+}
+
+/// What to do when an uploaded image is named like an image of the
+/// project already.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CollisionPolicy {
+	/// Refuse the upload.
+	Reject,
+	/// Replace the image, and index the new one instead.
+	#[default]
+	Overwrite,
+	/// Store the image under a free name, like `name_1.png`.
+	Rename,
+}
+
+/// What to do when an uploaded image is a near-duplicate of an image of
+/// the project.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+	/// Refuse the upload.
+	Reject,
+	/// Store the image anyway, and report the duplicate.
+	Store,
+	/// Do not store the image, and answer with the existing one instead.
+	Link,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct UploadImageReq {
 	pub project_name: String,
     pub image_name: String,
 	pub data: String,
+	#[serde(default)]
+	pub hash_type: Option<HashType>, // hash type of a newly created project, PHASH if omitted.
+	#[serde(default)]
+	pub on_collision: CollisionPolicy, // when the image name is taken, overwrite if omitted.
+	#[serde(default)]
+	pub on_duplicate: Option<DuplicatePolicy>, // when the image is a near-duplicate, no check if omitted.
+	#[serde(default)]
+	pub duplicate_threshold: Option<f32>, // largest normalized distance (0..1) of a near-duplicate.
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct UploadImageResp {
 	pub success: bool,
 	pub message: String,
-	pub token: String,
+	pub token: String, // empty if linked to an existing image.
+	#[serde(default)]
+	pub image_name: String, // name the image is stored under, or of the image it is linked to.
+	#[serde(default)]
+	pub duplicate_of: Option<SimilarImageEntry>, // the closest near-duplicate found, if checked.
+}
+
+/// One image of a batch upload.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct BatchImage {
+	pub image_name: String,
+	#[serde(default)]
+	pub data: String, // base64 encoded image, unless sent as a file part.
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct UploadBatchReq {
+	pub images: Vec<BatchImage>,
+	#[serde(default)]
+	pub hash_type: Option<HashType>, // hash type of a newly created project, PHASH if omitted.
+	#[serde(default)]
+	pub on_collision: CollisionPolicy, // when an image name is taken, overwrite if omitted.
+}
+
+/// Outcome of one image of a batch upload.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct UploadBatchItem {
+	pub image_name: String,
+	pub success: bool,
+	pub message: String,
+	#[serde(default)]
+	pub token: Option<String>, // deletion token, if stored.
+	#[serde(default)]
+	pub stored_name: Option<String>, // name the image is stored under, if stored.
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct UploadBatchResp {
+	pub success: bool, // whether every image is stored.
+	pub message: String,
+	pub results: Vec<UploadBatchItem>, // in request order.
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct RemoveImageReq {
-	token: String, // image removal token.
+	pub token: String, // image removal token.
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct RemoveImageResp {
-	success: bool,
-	message: String,
+	pub success: bool,
+	pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+pub struct CreateProjectReq {
+	#[serde(default)]
+	pub hash_type: Option<HashType>, // PHASH if omitted.
+	#[serde(default)]
+	pub extra_hash_types: Vec<HashType>, // additional hashes indexed for fused scoring.
+	#[serde(default)]
+	pub hasher_config: Option<HasherConfig>, // hash / resize sizes and filter, defaults if omitted.
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct CreateProjectResp {
+	pub success: bool,
+	pub message: String,
+	pub hash_type: Option<HashType>, // hash type of the project, if it exists.
+	#[serde(default)]
+	pub extra_hash_types: Vec<HashType>,
+	#[serde(default)]
+	pub hasher_config: Option<HasherConfig>,
+}
+
+/// How far a project is in loading, in images.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct LoadProgress {
+	pub done: usize,
+	pub total: usize,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -60,6 +287,185 @@ pub struct DeleteProjectResp {
 	pub message: String,
 }
 
+/// Where a project is in loading.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProjectLoadState {
+	Loading,
+	Ready,
+	Failed,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ProjectInfo {
+	pub project_name: String,
+	pub state: ProjectLoadState,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub progress: Option<LoadProgress>, // only while loading.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>, // only if loading failed.
+	pub image_count: Option<usize>, // indexed images, once loaded.
+	pub hash_type: Option<HashType>,
+	#[serde(default)]
+	pub extra_hash_types: Vec<HashType>,
+	pub hasher_config: Option<HasherConfig>,
+	pub disk_size: u64, // bytes taken by the project folder.
+	pub last_modified: Option<u64>, // unix time in seconds of the last change in the folder.
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ListProjectsResp {
+	pub success: bool,
+	pub message: String,
+	pub projects: Vec<ProjectInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct GetProjectResp {
+	pub success: bool,
+	pub message: String,
+	pub project: ProjectInfo,
+}
+
+/// What images are sorted by when listed.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageSortKey {
+	#[default]
+	Name,
+	Size,
+	Modified,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+	#[default]
+	Asc,
+	Desc,
+}
+
+/// Query string of the image listing.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct ListImagesQuery {
+	#[serde(default)]
+	pub page: Option<usize>, // 1-based, first page if omitted.
+	#[serde(default)]
+	pub page_size: Option<usize>,
+	#[serde(default)]
+	pub filter: Option<String>, // case-insensitive part of the file name.
+	#[serde(default)]
+	pub sort: ImageSortKey,
+	#[serde(default)]
+	pub order: SortOrder,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+	pub image_name: String,
+	pub size: u64, // bytes, when the image was indexed.
+	pub last_modified: Option<u64>, // unix time in seconds, when the image was indexed.
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ListImagesResp {
+	pub success: bool,
+	pub message: String,
+	pub total: usize, // images matching the filter, across pages.
+	pub page: usize,
+	pub page_size: usize,
+	pub images: Vec<ImageInfo>,
+}
+
+/// Query string of the hash export.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct HashesQuery {
+	#[serde(default)]
+	pub encoding: HashEncoding,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ImageHashInfo {
+	pub image_name: String,
+	pub hashes: BTreeMap<HashType, String>, // encoded hash per hash type.
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ProjectHashesResp {
+	pub success: bool,
+	pub message: String,
+	pub project_name: String,
+	pub hasher_config: HasherConfig,
+	pub encoding: HashEncoding,
+	pub bit_lengths: BTreeMap<HashType, usize>, // bits of the hashes of each hash type.
+	pub images: Vec<ImageHashInfo>, // sorted by name.
+}
+
+/// Query string of the thumbnail.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct ThumbnailQuery {
+	#[serde(default)]
+	pub size: Option<u32>, // longest side in pixels.
+}
+
+/// Query string of the duplicate report.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct DuplicatesQuery {
+	#[serde(default)]
+	pub threshold: Option<f32>, // largest normalized distance (0..0.25) of near-duplicates.
+}
+
+/// Distance between two images of a duplicate cluster.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DuplicatePair {
+	pub image_a: String,
+	pub image_b: String,
+	pub distance: f32,
+	pub similarity: f32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DuplicateClusterInfo {
+	pub images: Vec<String>, // sorted by name.
+	pub distances: Vec<DuplicatePair>, // pairs of near-duplicates linking the images.
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DuplicatesResp {
+	pub success: bool,
+	pub message: String,
+	pub project_name: String,
+	pub image_count: usize, // indexed images.
+	pub duplicate_count: usize, // images in clusters.
+	#[serde(default)]
+	pub truncated: bool, // whether the search stopped at the pair cap, leaving near-duplicates out.
+	pub clusters: Vec<DuplicateClusterInfo>, // largest first.
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct RescanProjectReq {
+	#[serde(default)]
+	pub force: bool, // rehash every image, even those unchanged on disk.
+}
+
+/// An image which could not be hashed during a rescan.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RescanFailure {
+	pub image_name: String,
+	pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RescanProjectResp {
+	pub success: bool,
+	pub message: String,
+	pub added: Vec<String>, // images found on disk, not indexed before.
+	pub changed: Vec<String>, // images whose hashes changed.
+	pub removed: Vec<String>, // indexed images which are gone from disk.
+	pub unchanged: usize,
+	pub failed: Vec<RescanFailure>,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -81,20 +487,25 @@ mod tests {
         println!("--- Testing CompareImageResp ---");
         let ent1: SimilarImageEntry = SimilarImageEntry {
             image_name: "img01".to_owned(),
+            project_name: "some_project".to_owned(),
             distance: 3.0,
+            similarity: 0.99,
             data: None,
         };
 
         let ent2: SimilarImageEntry = SimilarImageEntry {
             image_name: "img02".to_owned(),
+            project_name: "other_project".to_owned(),
             distance: 8.7,
+            similarity: 0.5,
             data: Some(smallest_png_1.clone()),
         };
 
         let comp_resp: CompareImageResp = CompareImageResp {
-            project_name: "some_project".to_owned(),
+            project_name: "*".to_owned(),
             success: true,
             message: "success".to_owned(),
+            projects: vec!["other_project".to_owned(), "some_project".to_owned()],
             compare_result: vec![ent1, ent2],
         };
 
@@ -106,8 +517,14 @@ mod tests {
         println!("--- Testing CompareImageReq ---");
         let comp_req: CompareImageReq = CompareImageReq {
             project_name: "some_project".to_owned(),
+            project_names: None,
             data: smallest_gif_2.clone(),
+            query_hash: None,
             with_image: true,
+            weights: Some(HashMap::from([(HashType::PHASH, 2.0), (HashType::DHASH, 1.0)])),
+            top_k: Some(20),
+            max_distance: None,
+            max_normalized_distance: Some(0.25),
         };
 
         let comp_req_json: String = serde_json::to_string_pretty(&comp_req).unwrap();
@@ -115,6 +532,14 @@ mod tests {
         println!("{}\n", comp_req_json);
         assert_eq!(comp_req, comp_req_deserialized);
 
+        // a hash query comes without image data.
+        let hash_req: CompareImageReq = serde_json::from_str(
+            r#"{"project_name": "some_project", "query_hash": {"hashes": {"PHASH": "a1c0"}, "encoding": "hex"}}"#).unwrap();
+        let query_hash = hash_req.query_hash.unwrap();
+        assert_eq!(hash_req.data, "");
+        assert_eq!(query_hash.hashes[&HashType::PHASH], "a1c0");
+        assert_eq!(query_hash.hasher_config, None);
+
 
         // ---------------------------------------------------------
         // 2. Test UploadImageReq & UploadImageResp
@@ -124,6 +549,10 @@ mod tests {
             project_name: "some_project".to_owned(),
             image_name: "test.png".to_owned(),
             data: smallest_png_1.clone(),
+            hash_type: Some(HashType::DHASH),
+            on_collision: CollisionPolicy::Rename,
+            on_duplicate: Some(DuplicatePolicy::Link),
+            duplicate_threshold: Some(0.1),
         };
 
         let upload_req_json: String = serde_json::to_string_pretty(&upload_req).unwrap();
@@ -131,12 +560,19 @@ mod tests {
         println!("{}\n", upload_req_json);
         assert_eq!(upload_req, upload_req_deserialized);
 
+        // `hash_type` is optional, older clients do not send it.
+        let upload_req_legacy: UploadImageReq = serde_json::from_str(
+            r#"{"project_name": "some_project", "image_name": "test.png", "data": ""}"#).unwrap();
+        assert_eq!(upload_req_legacy.hash_type, None);
+
 
         println!("--- Testing UploadImageResp ---");
         let upload_resp: UploadImageResp = UploadImageResp {
             success: true,
             message: "image uploaded and indexed successfully".to_owned(),
             token: "abc-123-unique-token-xyz".to_owned(),
+            image_name: "test_1.png".to_owned(),
+            duplicate_of: None,
         };
 
         let upload_resp_json: String = serde_json::to_string_pretty(&upload_resp).unwrap();
@@ -148,6 +584,14 @@ mod tests {
             success: false,
             message: "duplication".to_owned(),
             token: "".to_owned(),
+            image_name: "scan_01.png".to_owned(),
+            duplicate_of: Some(SimilarImageEntry {
+                image_name: "scan_01.png".to_owned(),
+                project_name: "some_project".to_owned(),
+                distance: 2.0,
+                similarity: 0.998,
+                data: None,
+            }),
         };
 
         let upload_resp2_json: String = serde_json::to_string_pretty(&upload_resp2).unwrap();
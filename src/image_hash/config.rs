@@ -0,0 +1,164 @@
+use image::imageops::FilterType;
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+use super::HashType;
+
+/// Largest width / height accepted for hash and resized image.
+///
+/// pHash runs a naive DCT on every row, so the cost grows fast.
+pub const MAX_HASHER_SIZE: u32 = 128;
+
+/// Resize filter applied before hashing.
+///
+/// It mirrors `image::imageops::FilterType`, which cannot serialize.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(value: ResizeFilter) -> Self {
+        match value {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// `imagehash` only takes plain function pointers as resizer, so we need
+/// one function per filter.
+pub(crate) type Resizer = fn(&DynamicImage, usize, usize) -> DynamicImage;
+
+impl ResizeFilter {
+    pub(crate) fn resizer(&self) -> Resizer {
+        match self {
+            ResizeFilter::Nearest => |img, w, h| img.resize_exact(w as u32, h as u32, FilterType::Nearest),
+            ResizeFilter::Triangle => |img, w, h| img.resize_exact(w as u32, h as u32, FilterType::Triangle),
+            ResizeFilter::CatmullRom => |img, w, h| img.resize_exact(w as u32, h as u32, FilterType::CatmullRom),
+            ResizeFilter::Gaussian => |img, w, h| img.resize_exact(w as u32, h as u32, FilterType::Gaussian),
+            ResizeFilter::Lanczos3 => |img, w, h| img.resize_exact(w as u32, h as u32, FilterType::Lanczos3),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ResizeFilter::Nearest => "nearest",
+            ResizeFilter::Triangle => "triangle",
+            ResizeFilter::CatmullRom => "catmullrom",
+            ResizeFilter::Gaussian => "gaussian",
+            ResizeFilter::Lanczos3 => "lanczos3",
+        }
+    }
+}
+
+/// Parameters of a hasher.
+///
+/// The image is first resized to `image_width` x `image_height` with
+/// `filter`, then a hash of `hash_width` x `hash_height` is taken from it.
+/// Missing fields fall back to the default, which is what every project
+/// used before the config was adjustable.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HasherConfig {
+    pub hash_width: u32,
+    pub hash_height: u32,
+    pub image_width: u32,
+    pub image_height: u32,
+    pub filter: ResizeFilter,
+}
+
+impl Default for HasherConfig {
+    fn default() -> Self {
+        HasherConfig {
+            hash_width: 32,
+            hash_height: 32,
+            image_width: 32,
+            image_height: 32,
+            // for resizer function, we choose a more smooth one.
+            filter: ResizeFilter::Lanczos3,
+        }
+    }
+}
+
+impl HasherConfig {
+    /// Check that every size is usable by hashers of `hash_types`.
+    ///
+    /// `imagehash` silently clips a hash which does not fit in the resized
+    /// image, so such configs are refused. The default config is kept as is,
+    /// its hashes are stored by every existing project.
+    pub fn validate(&self, hash_types: &[HashType]) -> Result<(), String> {
+        let sizes = [
+            ("hash_width", self.hash_width),
+            ("hash_height", self.hash_height),
+            ("image_width", self.image_width),
+            ("image_height", self.image_height),
+        ];
+
+        for (name, size) in sizes {
+            // dHash compares neighbour pixels, so 2 is the least we can do.
+            if !(2..=MAX_HASHER_SIZE).contains(&size) {
+                return Err(format!("{} must be between 2 and {}, got {}", name, MAX_HASHER_SIZE, size));
+            }
+        }
+
+        if *self == HasherConfig::default() {
+            return Ok(());
+        }
+
+        if self.hash_width > self.image_width || self.hash_height > self.image_height {
+            return Err(format!("hash of {}x{} does not fit in image of {}x{}",
+                self.hash_width, self.hash_height, self.image_width, self.image_height));
+        }
+
+        // aHash needs the image as wide as the hash, the others need it
+        // wider, so no custom config suits both.
+        let has_ahash = hash_types.contains(&HashType::AHASH);
+        if let Some(other) = hash_types.iter().find(|t| matches!(t, HashType::DHASH | HashType::PHASH))
+            && has_ahash {
+            return Err(format!("AHASH and {:?} cannot share a custom hasher_config, \
+                keep the default config or index them in separate projects", other));
+        }
+
+        for hash_type in hash_types {
+            match hash_type {
+                // dHash compares neighbour pixels, and pHash skips the DC term,
+                // so both need one more column than the hash.
+                HashType::DHASH | HashType::PHASH if self.image_width <= self.hash_width => {
+                    return Err(format!("{:?} needs image_width larger than hash_width, got {} and {}",
+                        hash_type, self.image_width, self.hash_width));
+                },
+                // aHash keeps every pixel of a square of image_width, and
+                // takes their mean on the hash size only.
+                HashType::AHASH if self.hash_width != self.image_width || self.hash_height != self.image_width => {
+                    return Err(format!("{:?} needs a hash of image_width x image_width, got {}x{} and {}",
+                        hash_type, self.hash_width, self.hash_height, self.image_width));
+                },
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Tag which tells cache files of different configs apart.
+    ///
+    /// The default config has no tag, so caches written before the config
+    /// was adjustable stay valid.
+    pub fn cache_tag(&self) -> Option<String> {
+        match *self == HasherConfig::default() {
+            true => None,
+            false => Some(format!("{}x{}-{}x{}-{}",
+                self.hash_width, self.hash_height,
+                self.image_width, self.image_height,
+                self.filter.name())),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+/// Prefix of cache records written in the packed format.
+///
+/// Legacy records are a bare bincode `Vec<bool>`, which starts with the bit
+/// count and continues with `0` / `1` bytes, so it can never start with
+/// these four bytes.
+const PACKED_MAGIC: [u8; 4] = *b"VMH1";
+
+/// Text encoding of a hash exchanged with clients.
+///
+/// Bits are packed into bytes most significant bit first, the way most
+/// hash libraries print them. The bit length is not part of the text, so
+/// it has to be known to decode.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+/// We make a proxy struct for `imagehash::Hash` because it is
+/// so bad, it cannot serialize, cannot measure distance, and
+/// even cannot clone.
+///
+/// The lack of `clone` ability actually drives me nut.
+///
+/// Bits are packed into `u64` words, least significant bit first, so the
+/// hamming distance is a few XOR + popcount instead of a walk over a
+/// `Vec<bool>`. Unused bits of the last word are always zero.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Hash {
+    /// Number of bits of the hash.
+    len: usize,
+    /// The packed bits.
+    words: Vec<u64>,
+}
+
+/// The cache record format before bits were packed.
+#[derive(Serialize, Deserialize)]
+struct LegacyHash {
+    bits: Vec<bool>,
+}
+
+impl Hash {
+    /// Pack a bit vector.
+    pub fn from_bits(bits: &[bool]) -> Self {
+        let mut words = vec![0u64; bits.len().div_ceil(64)];
+        for (i, bit) in bits.iter().enumerate() {
+            if *bit {
+                words[i / 64] |= 1 << (i % 64);
+            }
+        }
+        Hash { len: bits.len(), words }
+    }
+
+    /// Unpack into a bit vector.
+    pub fn to_bits(&self) -> Vec<bool> {
+        (0..self.len)
+            .map(|i| self.words[i / 64] & (1 << (i % 64)) != 0)
+            .collect()
+    }
+
+    /// Number of bits of the hash.
+    pub fn bit_len(&self) -> usize {
+        self.len
+    }
+
+    /// The packed words, least significant bit first.
+    pub fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    /// Count differing bits.
+    ///
+    /// Hashes of different length are compared on their common prefix.
+    pub fn hamming(&self, other: &Self) -> u32 {
+        let len = self.len.min(other.len);
+        let (full_words, rest_bits) = (len / 64, len % 64);
+
+        let mut diff: u32 = self.words[..full_words].iter()
+            .zip(other.words[..full_words].iter())
+            .map(|(x, y)| (x ^ y).count_ones())
+            .sum();
+
+        if rest_bits > 0 {
+            let mask = (1u64 << rest_bits) - 1;
+            diff += ((self.words[full_words] ^ other.words[full_words]) & mask).count_ones();
+        }
+
+        diff
+    }
+
+    /// Pack into bytes, most significant bit first.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; self.len.div_ceil(8)];
+        for i in 0..self.len {
+            if self.words[i / 64] & (1 << (i % 64)) != 0 {
+                bytes[i / 8] |= 0x80 >> (i % 8);
+            }
+        }
+        bytes
+    }
+
+    /// Unpack `bit_len` bits from bytes, most significant bit first.
+    pub fn from_bytes(bytes: &[u8], bit_len: usize) -> Result<Self, Box<dyn Error>> {
+        if bytes.len() != bit_len.div_ceil(8) {
+            return Err(format!("expected {} bytes for {} bits, got {}",
+                                bit_len.div_ceil(8), bit_len, bytes.len()).into());
+        }
+
+        let bits: Vec<bool> = (0..bit_len)
+            .map(|i| bytes[i / 8] & (0x80 >> (i % 8)) != 0)
+            .collect();
+        Ok(Hash::from_bits(&bits))
+    }
+
+    /// Encode as text.
+    pub fn encode(&self, encoding: HashEncoding) -> String {
+        use base64::{engine::general_purpose, Engine};
+
+        let bytes = self.to_bytes();
+        match encoding {
+            HashEncoding::Hex => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+            HashEncoding::Base64 => general_purpose::STANDARD.encode(bytes),
+        }
+    }
+
+    /// Decode a hash of `bit_len` bits from text.
+    pub fn decode(text: &str, encoding: HashEncoding, bit_len: usize) -> Result<Self, Box<dyn Error>> {
+        use base64::{engine::general_purpose, Engine};
+
+        let text = text.trim();
+        let bytes: Vec<u8> = match encoding {
+            HashEncoding::Hex => {
+                if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err("invalid hex string".into());
+                }
+                (0..text.len()).step_by(2)
+                    .map(|i| u8::from_str_radix(&text[i..i + 2], 16))
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| "invalid hex string")?
+            },
+            HashEncoding::Base64 => general_purpose::STANDARD.decode(text)?,
+        };
+
+        Hash::from_bytes(&bytes, bit_len)
+    }
+
+    /// Encode as a cache record.
+    pub fn encode_cache(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut data = PACKED_MAGIC.to_vec();
+        let body = bincode::serde::encode_to_vec(self, bincode::config::standard())
+            .map_err(|e| format!("error while serialize ({})", e))?;
+        data.extend(body);
+        Ok(data)
+    }
+
+    /// Decode a cache record, either packed or legacy.
+    pub fn decode_cache(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        match data.strip_prefix(&PACKED_MAGIC) {
+            Some(body) => {
+                let (h, _): (Hash, usize) =
+                    bincode::serde::decode_from_slice(body, bincode::config::standard())?;
+
+                if h.words.len() != h.len.div_ceil(64) {
+                    return Err(format!("corrupted hash: {} words for {} bits", h.words.len(), h.len).into());
+                }
+                Ok(h)
+            },
+            None => {
+                let (h, _): (LegacyHash, usize) =
+                    bincode::serde::decode_from_slice(data, bincode::config::standard())?;
+                Ok(Hash::from_bits(&h.bits))
+            },
+        }
+    }
+}
+
+impl From<imagehash::Hash> for Hash {
+    fn from(value: imagehash::Hash) -> Self {
+        Hash::from_bits(&value.bits)
+    }
+}
+
+/// Hamming distance between two packed hashes.
+impl crate::metric::Metrizable for Hash {
+    fn dist(&self, other: &Self) -> f64 {
+        self.hamming(other) as f64
+    }
+}
+
+/// Hamming distance of two hashes is bounded by their bit length.
+impl crate::metric::BoundedVariation for Hash {
+    fn min(&self) -> f64 {
+        0.0
+    }
+
+    fn max(&self) -> f64 {
+        self.len as f64
+    }
+}
+
+impl crate::metric::BoundedMetrizable for Hash { }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric::Metrizable;
+
+    #[test]
+    fn test_pack_and_hamming() {
+        // 130 bits spans three words, the last one partially used.
+        let lhs: Vec<bool> = (0..130).map(|i| i % 3 == 0).collect();
+        let rhs: Vec<bool> = (0..130).map(|i| i % 3 == 0 || i == 5 || i == 70 || i == 128).collect();
+
+        let (lhs_h, rhs_h) = (Hash::from_bits(&lhs), Hash::from_bits(&rhs));
+        assert_eq!(lhs_h.bit_len(), 130);
+        assert_eq!(lhs_h.words().len(), 3);
+        assert_eq!(lhs_h.to_bits(), lhs);
+
+        // same as the old walk over bit vectors
+        let expected = lhs.iter().zip(rhs.iter()).filter(|(x, y)| x != y).count();
+        assert_eq!(lhs_h.dist(&rhs_h), expected as f64);
+        assert_eq!(lhs_h.dist(&rhs_h), 3.0);
+    }
+
+    #[test]
+    fn test_text_encoding() {
+        // 12 bits, the last byte is half used.
+        let bits = [true, false, true, false, false, false, false, true, true, true, false, false];
+        let h = Hash::from_bits(&bits);
+
+        assert_eq!(h.to_bytes(), vec![0xa1, 0xc0]);
+        assert_eq!(h.encode(HashEncoding::Hex), "a1c0");
+        assert_eq!(h.encode(HashEncoding::Base64), "ocA=");
+
+        assert_eq!(Hash::decode("A1C0", HashEncoding::Hex, 12).unwrap(), h);
+        assert_eq!(Hash::decode("ocA=", HashEncoding::Base64, 12).unwrap(), h);
+
+        // wrong length, or not hex at all
+        assert!(Hash::decode("a1c0", HashEncoding::Hex, 32).is_err());
+        assert!(Hash::decode("a1cg", HashEncoding::Hex, 12).is_err());
+        assert!(Hash::decode("a1c", HashEncoding::Hex, 12).is_err());
+    }
+
+    #[test]
+    fn test_cache_record() {
+        let bits: Vec<bool> = (0..992).map(|i| i % 7 < 3).collect();
+        let h = Hash::from_bits(&bits);
+
+        // packed record round trip
+        let data = h.encode_cache().unwrap();
+        assert_eq!(Hash::decode_cache(&data).unwrap(), h);
+
+        // legacy record, as written before bits were packed
+        let legacy = bincode::serde::encode_to_vec(
+            LegacyHash { bits: bits.clone() }, bincode::config::standard()).unwrap();
+        assert_eq!(Hash::decode_cache(&legacy).unwrap(), h);
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use super::{
+    calc_distance_from_hash,
+    Hash,
+    HashType,
+    HashWeights,
+    ImageDistEntry,
+    ImageHashEntry,
+    SearchLimits,
+};
+
+/// Rebuilding a tree smaller than this is not worth it.
+const MIN_REBUILD_SIZE: usize = 64;
+
+/// Slack added to pruning radius, so float rounding of fused distances
+/// never prunes an entry that should be kept.
+const RADIUS_EPSILON: f64 = 1e-9;
+
+/// A node of the BK-tree, owning one image entry.
+#[derive(Debug, Clone)]
+struct Node {
+    entry: ImageHashEntry,
+    /// Removed nodes stay in the tree to keep routing intact, until the
+    /// next rebuild.
+    alive: bool,
+    /// Children keyed by their hamming distance to this node.
+    children: BTreeMap<u32, usize>,
+}
+
+/// Nearest-neighbour index of the images of a project.
+///
+/// It is a BK-tree in the hamming space of one hash type, usually the
+/// primary hash type of the project. Searches only visit subtrees which
+/// may hold an entry within reach, instead of measuring every image.
+///
+/// Entries are inserted and removed incrementally. A removal only marks
+/// the node, and the tree is rebuilt once half of its nodes are removed.
+#[derive(Debug, Clone)]
+pub struct HashIndex {
+    /// The hash type the tree is keyed on.
+    hash_type: HashType,
+    /// Node 0 is the root.
+    nodes: Vec<Node>,
+    /// Image name -> node of alive entries.
+    slots: HashMap<PathBuf, usize>,
+}
+
+impl HashIndex {
+    /// Make an empty index keyed on given hash type.
+    pub fn new(hash_type: HashType) -> Self {
+        HashIndex { hash_type, nodes: vec![], slots: HashMap::new() }
+    }
+
+    /// Build an index from a list of entries.
+    ///
+    /// Entries without a hash of the keyed type cannot be indexed, and
+    /// are skipped.
+    pub fn from_entries(hash_type: HashType, entries: Vec<ImageHashEntry>) -> Self {
+        let mut index = HashIndex::new(hash_type);
+        for entry in entries {
+            if let Err(err) = index.insert(entry) {
+                println!("[!] {}", err);
+            }
+        }
+        index
+    }
+
+    /// The hash type the index is keyed on.
+    pub fn hash_type(&self) -> HashType {
+        self.hash_type
+    }
+
+    /// Number of indexed images.
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Look an image up by its name.
+    pub fn get(&self, image_name: &Path) -> Option<&ImageHashEntry> {
+        self.slots.get(image_name).map(|&i| &self.nodes[i].entry)
+    }
+
+    /// Every indexed image, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &ImageHashEntry> {
+        self.nodes.iter().filter(|n| n.alive).map(|n| &n.entry)
+    }
+
+    /// Index an image, replacing the entry of the same name if any.
+    pub fn insert(&mut self, entry: ImageHashEntry) -> Result<(), Box<dyn Error>> {
+        let Some(hash) = entry.hashes.get(&self.hash_type) else {
+            return Err(format!("cannot index <{}> without {:?} hash",
+                entry.image_name.display(), self.hash_type).into());
+        };
+
+        self.remove(&entry.image_name);
+
+        let new_node = self.nodes.len();
+
+        // walk down to the first free edge
+        let mut cur = 0;
+        while cur < new_node {
+            let d = self.nodes[cur].entry.hashes[&self.hash_type].hamming(hash);
+            match self.nodes[cur].children.get(&d) {
+                Some(&child) => cur = child,
+                None => {
+                    self.nodes[cur].children.insert(d, new_node);
+                    break;
+                },
+            }
+        }
+
+        self.slots.insert(entry.image_name.clone(), new_node);
+        self.nodes.push(Node { entry, alive: true, children: BTreeMap::new() });
+
+        Ok(())
+    }
+
+    /// Drop an image from the index, returns its entry if it was indexed.
+    pub fn remove(&mut self, image_name: &Path) -> Option<ImageHashEntry> {
+        let i = self.slots.remove(image_name)?;
+        self.nodes[i].alive = false;
+        let entry = self.nodes[i].entry.clone();
+
+        if self.nodes.len() >= MIN_REBUILD_SIZE && self.slots.len() * 2 < self.nodes.len() {
+            self.rebuild();
+        }
+
+        Some(entry)
+    }
+
+    /// Rebuild the tree from alive entries only.
+    fn rebuild(&mut self) {
+        let entries: Vec<ImageHashEntry> = std::mem::take(&mut self.nodes).into_iter()
+            .filter(|n| n.alive)
+            .map(|n| n.entry)
+            .collect();
+
+        self.slots.clear();
+        for entry in entries {
+            // every entry was indexed before, so it has the keyed hash.
+            self.insert(entry).ok();
+        }
+    }
+
+    /// Find the closest images to given hashes within `limits`, closest
+    /// first, ranked by the fused distance of `weights`.
+    ///
+    /// The distance on the keyed hash type, scaled by its share of the
+    /// weights, is a lower bound of the fused distance. So a subtree is
+    /// skipped when no entry in it can get under the thresholds, or beat
+    /// the current top-K. If the keyed hash type is not weighted, every
+    /// entry is measured.
+    pub fn search(&self, hashes: &BTreeMap<HashType, Hash>, weights: &HashWeights, limits: &SearchLimits)
+        -> Vec<ImageDistEntry> {
+
+        if self.is_empty() || limits.top_k == 0 {
+            return vec![];
+        }
+
+        let share = weights.share_of(self.hash_type);
+        let query = match hashes.get(&self.hash_type) {
+            Some(q) if share > 0.0 => q,
+            _ => {
+                let dist_vec = self.iter()
+                    .filter_map(|h_ent| calc_distance_from_hash(hashes, weights, h_ent))
+                    .collect();
+                return limits.apply(dist_vec);
+            },
+        };
+
+        // reach of the thresholds, measured on the keyed hash type
+        let mut reach = f64::INFINITY;
+        if let Some(d) = limits.max_distance {
+            reach = reach.min(d / share);
+        }
+        if let Some(d) = limits.max_normalized_distance {
+            reach = reach.min(d * query.bit_len() as f64 / share);
+        }
+
+        // max-heap, the farthest of current top-K on top.
+        let mut best: BinaryHeap<ImageDistEntry> = BinaryHeap::new();
+        let mut stack: Vec<usize> = vec![0];
+
+        while let Some(i) = stack.pop() {
+            let node = &self.nodes[i];
+            let d = node.entry.hashes[&self.hash_type].hamming(query) as f64;
+
+            if node.alive
+                && let Some(dist) = calc_distance_from_hash(hashes, weights, &node.entry)
+                && limits.accepts(&dist) {
+                if best.len() < limits.top_k {
+                    best.push(dist);
+                } else if best.peek().is_some_and(|worst| dist < *worst) {
+                    best.pop();
+                    best.push(dist);
+                }
+            }
+
+            let radius = match best.peek() {
+                Some(worst) if best.len() >= limits.top_k => reach.min(worst.distance / share),
+                _ => reach,
+            } + RADIUS_EPSILON;
+
+            // entries under edge `e` are exactly `e` away from this node,
+            // so they are at least `|d - e|` away from the query.
+            let lo = (d - radius).ceil().max(0.0) as u32;
+            let hi = (d + radius).floor().min(u32::MAX as f64) as u32;
+            stack.extend(node.children.range(lo..=hi).map(|(_, &child)| child));
+        }
+
+        best.into_sorted_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_hash::calc_similarity_list_from_hash;
+
+    /// Tiny deterministic generator, so the test needs no extra crate.
+    fn next_rand(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    fn mk_entry(i: usize, state: &mut u64) -> ImageHashEntry {
+        let mut hashes = BTreeMap::new();
+        for hash_type in [HashType::PHASH, HashType::DHASH] {
+            let bits: Vec<bool> = (0..96).map(|_| next_rand(state).is_multiple_of(4)).collect();
+            hashes.insert(hash_type, Hash::from_bits(&bits));
+        }
+        ImageHashEntry { image_name: PathBuf::from(format!("{}.png", i)), hashes }
+    }
+
+    fn distances(dist_vec: &[ImageDistEntry]) -> Vec<f64> {
+        dist_vec.iter().map(|x| x.distance).collect()
+    }
+
+    #[test]
+    fn test_search_matches_linear_scan() {
+        let mut state = 0x9e3779b97f4a7c15;
+        let entries: Vec<ImageHashEntry> = (0..300).map(|i| mk_entry(i, &mut state)).collect();
+
+        let mut index = HashIndex::from_entries(HashType::PHASH, entries.clone());
+        let mut linear = entries;
+
+        // drop enough entries to trigger a rebuild
+        for i in (0..300).step_by(3).chain((1..300).step_by(3)) {
+            let name = PathBuf::from(format!("{}.png", i));
+            assert!(index.remove(&name).is_some());
+            linear.retain(|h| h.image_name != name);
+        }
+        assert!(index.remove(Path::new("0.png")).is_none());
+        assert_eq!(index.len(), 100);
+        assert!(index.nodes.len() < 300);
+
+        let uniform = HashWeights::uniform(&[HashType::PHASH, HashType::DHASH]);
+        let requested = HashMap::from([(HashType::PHASH, 1.0), (HashType::DHASH, 3.0)]);
+        let weighted = HashWeights::from_requested(&[HashType::PHASH, HashType::DHASH], &requested).unwrap();
+        let dhash_only = HashWeights::uniform(&[HashType::DHASH]);
+
+        let limits = [
+            SearchLimits { top_k: 5, max_distance: None, max_normalized_distance: None },
+            SearchLimits { top_k: 1000, max_distance: Some(30.0), max_normalized_distance: None },
+            SearchLimits { top_k: 3, max_distance: None, max_normalized_distance: Some(0.3) },
+        ];
+
+        for _ in 0..5 {
+            let query = mk_entry(0, &mut state).hashes;
+            for weights in [&uniform, &weighted, &dhash_only] {
+                for limit in limits.iter() {
+                    let expected = limit.apply(calc_similarity_list_from_hash(&query, weights, &linear));
+                    let found = index.search(&query, weights, limit);
+                    assert_eq!(distances(&found), distances(&expected));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_insert_replaces_same_name() {
+        let mut state = 42;
+        let mut index = HashIndex::new(HashType::PHASH);
+
+        index.insert(mk_entry(1, &mut state)).unwrap();
+        let replacement = mk_entry(1, &mut state);
+        index.insert(replacement.clone()).unwrap();
+
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.get(Path::new("1.png")).unwrap().hashes, replacement.hashes);
+
+        // not indexable without the keyed hash type
+        let mut no_phash = mk_entry(2, &mut state);
+        no_phash.hashes.remove(&HashType::PHASH);
+        assert!(index.insert(no_phash).is_err());
+    }
+}
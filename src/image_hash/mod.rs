@@ -1,155 +1,163 @@
 pub mod traits;
+mod config;
+mod hash;
+mod index;
+
+pub use config::{HasherConfig, ResizeFilter, MAX_HASHER_SIZE};
+pub use hash::{Hash, HashEncoding};
+pub use index::HashIndex;
 
 use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 
 
 use serde;
 use image::{self, DynamicImage};
-use std::fs::File;
 use std::path::{Path, PathBuf};
 use crate::image_hash::traits::Hasher;
 use crate::metric::*;
+use crate::utils::par_map;
 
 
 /// Enumerates all supported hash algorithm.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
 pub enum HashType {
     DHASH,
     PHASH,
     AHASH,
 }
 
-fn cache_ext(hash_type: HashType) -> String {
-    match hash_type {
+impl HashType {
+    /// Every supported hash type.
+    pub const ALL: [HashType; 3] = [HashType::DHASH, HashType::PHASH, HashType::AHASH];
+}
+
+/// Cache file extension of a hash type under given hasher config,
+/// e.g. `phash` for the default config, `phash-8x8-32x32-lanczos3` otherwise.
+fn cache_ext(hash_type: HashType, config: &HasherConfig) -> String {
+    let ext = match hash_type {
         HashType::DHASH => "dhash".to_owned(),
         HashType::PHASH => "phash".to_owned(),
         HashType::AHASH => "ahash".to_owned(),
+    };
+
+    match config.cache_tag() {
+        None => ext,
+        Some(tag) => format!("{}-{}", ext, tag),
     }
 }
 
-/// Make new hasher with default parameters.
-/// 
-/// TODO: make parameter adjustable
-pub fn mk_hasher(hash_type: HashType) -> Box<dyn Hasher> {
+/// Make new hasher with given parameters.
+pub fn mk_hasher(hash_type: HashType, config: &HasherConfig) -> Box<dyn Hasher> {
+    let (image_w, image_h) = (config.image_width as usize, config.image_height as usize);
+    let (hash_w, hash_h) = (config.hash_width as usize, config.hash_height as usize);
+    let resizer = config.filter.resizer();
+
     match hash_type {
         HashType::DHASH => {
             Box::new(imagehash::DifferenceHash::new()
-                .with_image_size(32, 32)
-                .with_hash_size(32, 32)
-                .with_resizer(|img, w, h| {
-                    // for resizer function, we choose a more smooth one.
-                    img.resize_exact(w as u32, h as u32, image::imageops::FilterType::Lanczos3)
-                }))
+                .with_image_size(image_w, image_h)
+                .with_hash_size(hash_w, hash_h)
+                .with_resizer(resizer))
         },
         HashType::PHASH => {
             Box::new(imagehash::PerceptualHash::new()
-                .with_image_size(32, 32)
-                .with_hash_size(32, 32)
-                .with_resizer(|img, w, h| {
-                    // for resizer function, we choose a more smooth one.
-                    img.resize_exact(w as u32, h as u32, image::imageops::FilterType::Lanczos3)
-                }))
+                .with_image_size(image_w, image_h)
+                .with_hash_size(hash_w, hash_h)
+                .with_resizer(resizer))
         },
         HashType::AHASH => {
             Box::new(imagehash::AverageHash::new()
-                .with_image_size(32, 32)
-                .with_hash_size(32, 32)
-                .with_resizer(|img, w, h| {
-                    // for resizer function, we choose a more smooth one.
-                    img.resize_exact(w as u32, h as u32, image::imageops::FilterType::Lanczos3)
-                }))
+                .with_image_size(image_w, image_h)
+                .with_hash_size(hash_w, hash_h)
+                .with_resizer(resizer))
         },
     }
 }
 
-/// We make a proxy struct for `imagehash::Hash` because it is 
-/// so bad, it cannot serialize, cannot measure distance, and
-/// even cannot clone. 
-/// 
-/// The lack of `clone` ability actually drives me nut.
-#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
-pub struct Hash {
-    /// The bit vector representation of the hash.
-    pub bits: Vec<bool>,
+fn calc_hash(image: &DynamicImage, hash_type: HashType, config: &HasherConfig) -> Hash {
+    let hasher = mk_hasher(hash_type, config);
+    hasher.hash(image).into()
 }
 
-impl From<imagehash::Hash> for Hash {
-    fn from(value: imagehash::Hash) -> Self {
-        Hash {
-            bits: value.bits.clone()
-        }
-    }
+/// Number of bits of the hashes a hasher gives.
+///
+/// `imagehash` does not always give `hash_width` x `hash_height` bits, e.g.
+/// dHash of the default config has one column less, so we just hash a
+/// blank image.
+pub fn hash_bit_len(hash_type: HashType, config: &HasherConfig) -> usize {
+    calc_hash(&DynamicImage::new_luma8(1, 1), hash_type, config).bit_len()
 }
 
-impl crate::metric::Metrizable for Hash {
-    fn dist(&self, other: &Self) -> f64 {
-        // we just borrow the already-implmented measure from Hash
-        // first make a cast
-
-        let self_hash: imagehash::Hash = imagehash::Hash {
-            bits: self.bits.clone()
-        };
+/// Calculate hashes of every given hash type for one image.
+pub fn calc_hashes(image: &DynamicImage, hash_types: &[HashType], config: &HasherConfig) -> BTreeMap<HashType, Hash> {
+    hash_types.iter()
+        .map(|&hash_type| (hash_type, calc_hash(image, hash_type, config)))
+        .collect()
+}
 
-        let other_hash: imagehash::Hash = imagehash::Hash {
-            bits: other.bits.clone()
-        };
+/// Calculate hashes of `count` images on a pool of `workers` threads.
+/// The `i`-th image is decoded by `load(i)` on the workers too.
+///
+/// Returns the hashes of every image, in order.
+pub fn calc_hashes_batch<F>(count: usize, hash_types: &[HashType], config: &HasherConfig, workers: usize, load: F)
+    -> Vec<Result<BTreeMap<HashType, Hash>, String>>
+where
+    F: Fn(usize) -> Result<DynamicImage, Box<dyn Error>> + Sync,
+{
+    let indices: Vec<usize> = (0..count).collect();
+
+    par_map(&indices, workers, |&i| {
+        let image = load(i).map_err(|e| format!("cannot decode image: {}", e))?;
+        Ok(calc_hashes(&image, hash_types, config))
+    })
+}
 
-        self_hash.dist(&other_hash)
-    }
+/// Distance of two images on one hash type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HashTypeDist {
+    pub hash_type: HashType,
+    pub distance: f64,
+    /// The distance scaled to [0, 1] by hash bounds.
+    pub norm_distance: f64,
 }
 
-fn calc_hash(image: &DynamicImage, hash_type: HashType) -> Hash {
-    let hasher = mk_hasher(hash_type);
-    hasher.hash(image).into()
+/// Measure two images with every given hash type, under given hasher config.
+pub fn calc_pair_distances(lhs: &DynamicImage, rhs: &DynamicImage, hash_types: &[HashType], config: &HasherConfig)
+    -> Vec<HashTypeDist> {
+    let (lhs, rhs) = (calc_hashes(lhs, hash_types, config), calc_hashes(rhs, hash_types, config));
+
+    hash_types.iter()
+        .map(|hash_type| {
+            let (l, r) = (&lhs[hash_type], &rhs[hash_type]);
+            HashTypeDist { hash_type: *hash_type, distance: l.dist(r), norm_distance: l.norm_dist(r) }
+        })
+        .collect()
 }
 
-pub fn calc_image_hash(image_path: &Path, hash_type: HashType) 
+pub fn calc_image_hash(image_path: &Path, hash_types: &[HashType], config: &HasherConfig) 
         -> Result<ImageHashEntry, Box<dyn Error>> {
 
     let img = image::open(image_path)?;
 
-    let h = calc_hash(&img, hash_type);
-
     Ok(ImageHashEntry { 
         image_name: image_path.to_owned(), 
-        hash_type, 
-        hash: h })
-}
-
-/// Write hash value to cache file in the same folder
-/// of image file located.
-pub fn write_hash_cache(image_path: &Path, image_hash: &Hash, hash_type: HashType) -> Result<usize, Box<dyn Error>> {
-
-    let image_path = image_path.to_owned();
-
-    let hash_file_name = image_path.with_added_extension(cache_ext(hash_type));
-
-    // Serialize: using proxy trick.
-    let hash_pxy = 
-        Hash { bits: image_hash.bits.clone() }; // clone to a already-derived (de)serialize struct.
-
-    let mut f_handle = File::create(hash_file_name)?;
-
-    bincode::serde::encode_into_std_write(
-                            &hash_pxy,
-                            &mut f_handle,
-                            bincode::config::standard())
-                                    .map_err(|e| format!("error while serialize ({})", e).into())
+        hashes: calc_hashes(&img, hash_types, config) })
 }
 
 /// Attempt to load hash value from cache in the same folder of 
 /// given image.
 /// 
-/// It also implemented the `Ord` trait so it's possible to sort a list
-/// of measured, images and fetch the most similar images.
-pub fn fetch_hash_cache(image_path: &Path, hash_type: HashType) -> Result<ImageHashEntry, Box<dyn Error>> {
+/// Hashes are kept in the project index now, sidecar caches are only read
+/// to import them.
+pub fn fetch_hash_cache(image_path: &Path, hash_type: HashType, config: &HasherConfig) -> Result<Hash, Box<dyn Error>> {
     
-    let hash_file_name = image_path.with_added_extension(cache_ext(hash_type));
+    let hash_file_name = image_path.with_added_extension(cache_ext(hash_type, config));
 
     // try to open the cache corresponding to the given hash type
-    let mut f_handle = match File::open(&hash_file_name) {
-        Ok(f) => f,
+    let data = match std::fs::read(&hash_file_name) {
+        Ok(d) => d,
         Err(e) => {
             // Provide a more descriptive error if the file doesn't exist
             return Err(format!("cannot open cache file '{}' with type {:?}: {}",
@@ -157,76 +165,178 @@ pub fn fetch_hash_cache(image_path: &Path, hash_type: HashType) -> Result<ImageH
         }
     };
 
-    // try to decode
-    let hash_pxy: Hash = 
-        bincode::serde::decode_from_std_read(
-        &mut f_handle,
-        bincode::config::standard(),
-        ).map_err(|e: bincode::error::DecodeError| format!("cannot deserialize cache file '{}' with type {:?}: {}",
+    // try to decode, caches written before bits were packed are still accepted.
+    let hash_pxy: Hash = Hash::decode_cache(&data)
+        .map_err(|e| format!("cannot deserialize cache file '{}' with type {:?}: {}",
                             hash_file_name.display(), hash_type, e))?;
 
-    let img_hash = Hash {
-        bits: hash_pxy.bits.clone(),
-    };
+    Ok(hash_pxy)
+}
 
-    Ok(ImageHashEntry { 
-        image_name: image_path.to_owned(), 
-        hash_type, 
-        hash: img_hash.into() 
-    })
+/// Whether the cache file of given image was written before the image
+/// was last modified, e.g. the image was replaced by hand.
+/// 
+/// A cache whose age cannot be told is considered stale.
+pub fn is_hash_cache_stale(image_path: &Path, hash_type: HashType, config: &HasherConfig) -> bool {
+
+    let hash_file_name = image_path.with_added_extension(cache_ext(hash_type, config));
+
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    match (modified(image_path), modified(&hash_file_name)) {
+        (Some(image_time), Some(cache_time)) => image_time > cache_time,
+        _ => true,
+    }
 }
 
-pub fn fetch_cache_or_calc_hash(image_path: &Path, hash_type: HashType, force_rewrite_cache: bool) -> Result<ImageHashEntry, Box<dyn Error>> {
-    
-    match fetch_hash_cache(image_path, hash_type) {
-        Ok(h) => { // we found exist hash cache
-            let h = match force_rewrite_cache {
-                true => { // force recalculate
-                    match calc_image_hash(image_path, hash_type) {
-                        Ok(h_new) => {
-                        // now try to write cache, and IGNORE the error.
-                        // [NOTE] shoule we catch the error of cache writing?
-                        // Hey, cache really looks like catch!
-                        write_hash_cache(image_path, &h.hash, hash_type).ok();
-                        h_new
-                    },
-                Err(_err) => h, // calculation error, just return cache
-            }
-                },
-                false => h,
-            };
-            Ok(h)
-        },
-        Err(_) => {
-            match calc_image_hash(image_path, hash_type) {
-                Ok(h) => {
-
-                    // now try to write cache, and IGNORE the error.
-                    // [NOTE] shoule we catch the error of cache writing?
-                    // Hey, cache really looks like catch!
-                    write_hash_cache(image_path, &h.hash, hash_type).ok();
-                    Ok(h)
-                },
-                Err(err) => Err(err),
-            }
-        },
+/// Remove the cache file of given image, if there is one.
+pub fn remove_hash_cache(image_path: &Path, hash_type: HashType, config: &HasherConfig) -> Result<(), Box<dyn Error>> {
+
+    let hash_file_name = image_path.with_added_extension(cache_ext(hash_type, config));
+
+    match std::fs::remove_file(&hash_file_name) {
+        Ok(_) => Ok(()),
+        // no cache, nothing to remove
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(format!("cannot remove cache file '{}': {}",
+                                hash_file_name.display(), e).into()),
     }
 }
 
-/// The definition of (image name, hash value) pair format.
+/// The definition of (image name, hash values) pair format.
+/// 
+/// An image can be hashed with several algorithms at once, keyed by
+/// their hash type.
 #[derive(Debug, Clone)]
 pub struct ImageHashEntry {
     pub image_name: PathBuf,
-    pub hash_type: HashType,
-    pub hash: Hash,
+    pub hashes: BTreeMap<HashType, Hash>,
+}
+
+/// Weights of each hash type when several hash distances are fused into
+/// one score.
+/// 
+/// The fused distance is the weighted mean of hamming distances of every
+/// hash type with a positive weight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashWeights {
+    weights: Vec<(HashType, f64)>,
+}
+
+impl HashWeights {
+    /// Same weight for every given hash type.
+    pub fn uniform(hash_types: &[HashType]) -> Self {
+        HashWeights {
+            weights: hash_types.iter().map(|&t| (t, 1.0)).collect(),
+        }
+    }
+
+    /// Check requested weights against the hash types a project has.
+    /// 
+    /// Weights must be finite and non-negative, at least one of them must
+    /// be positive, and every weighted hash type must be available.
+    pub fn from_requested(available: &[HashType], requested: &HashMap<HashType, f32>)
+        -> Result<Self, Box<dyn Error>> {
+
+        for (hash_type, w) in requested.iter() {
+            if !w.is_finite() || *w < 0.0 {
+                return Err(format!("invalid weight {} for {:?}", w, hash_type).into());
+            }
+            if !available.contains(hash_type) {
+                return Err(format!("hash type {:?} is not indexed, available: {:?}", hash_type, available).into());
+            }
+        }
+
+        // keep the order of `available`, and skip hash types weighted zero.
+        let weights: Vec<(HashType, f64)> = available.iter()
+            .filter_map(|t| requested.get(t).map(|&w| (*t, w as f64)))
+            .filter(|(_, w)| *w > 0.0)
+            .collect();
+
+        if weights.is_empty() {
+            return Err("at least one hash type needs a positive weight".into());
+        }
+
+        Ok(HashWeights { weights })
+    }
+
+    /// Hash types which take part in the fused score.
+    pub fn hash_types(&self) -> Vec<HashType> {
+        self.weights.iter().map(|(t, _)| *t).collect()
+    }
+
+    /// Share of given hash type in the total weight, 0 if not weighted.
+    pub fn share_of(&self, hash_type: HashType) -> f64 {
+        let total: f64 = self.weights.iter().map(|(_, w)| w).sum();
+        self.weights.iter()
+            .find(|(t, _)| *t == hash_type)
+            .map_or(0.0, |(_, w)| w / total)
+    }
+
+    /// Fuse distances between two sets of hashes, returns the pair of
+    /// (distance, normalized distance).
+    /// 
+    /// The normalized distance scales each hamming distance by the bounds
+    /// of its hash before fusing, so it always lies in [0, 1].
+    /// 
+    /// Returns `None` if one side misses a weighted hash type.
+    pub fn fused_dist(&self, lhs: &BTreeMap<HashType, Hash>, rhs: &BTreeMap<HashType, Hash>) -> Option<(f64, f64)> {
+        let mut acc = 0.0;
+        let mut acc_norm = 0.0;
+        let mut total_weight = 0.0;
+
+        for (hash_type, w) in self.weights.iter() {
+            let (l, r) = (lhs.get(hash_type)?, rhs.get(hash_type)?);
+            acc += w * l.dist(r);
+            acc_norm += w * l.norm_dist(r);
+            total_weight += w;
+        }
+
+        Some((acc / total_weight, acc_norm / total_weight))
+    }
 }
 
 /// The definition of an entry of image, pair with the distance 
 /// of another given image.
+/// 
+/// It also implemented the `Ord` trait so it's possible to sort a list
+/// of measured, images and fetch the most similar images.
 #[derive(Debug, Clone)]
 pub struct ImageDistEntry {
     pub image_name: PathBuf,
     pub distance: f64,
+    /// The distance scaled to [0, 1] by hash bounds.
+    pub norm_distance: f64,
+}
+
+/// How many, and how far results of a similarity search can be.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SearchLimits {
+    /// Keep at most this many closest entries.
+    pub top_k: usize,
+    /// Drop entries farther than this distance.
+    pub max_distance: Option<f64>,
+    /// Drop entries farther than this normalized distance.
+    pub max_normalized_distance: Option<f64>,
+}
+
+impl SearchLimits {
+    /// Check the distance thresholds, `top_k` is not considered here.
+    pub fn accepts(&self, entry: &ImageDistEntry) -> bool {
+        self.max_distance.is_none_or(|d| entry.distance <= d)
+            && self.max_normalized_distance.is_none_or(|d| entry.norm_distance <= d)
+    }
+
+    /// Keep the closest `top_k` entries within thresholds, closest first.
+    pub fn apply(&self, dist_vec: Vec<ImageDistEntry>) -> Vec<ImageDistEntry> {
+        let mut dist_vec: Vec<ImageDistEntry> = dist_vec.into_iter()
+            .filter(|x| self.accepts(x))
+            .collect();
+
+        dist_vec.sort();
+        dist_vec.truncate(self.top_k);
+        dist_vec
+    }
 }
 
 impl PartialEq for ImageDistEntry {
@@ -238,8 +348,8 @@ impl PartialEq for ImageDistEntry {
 
 impl PartialOrd for ImageDistEntry {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        // You can use standard partial_cmp here, though total_cmp is also fine.
-        self.distance.partial_cmp(&other.distance)
+        // Delegate to `Ord` so both orderings always agree.
+        Some(self.cmp(other))
     }
 }
 
@@ -252,41 +362,213 @@ impl Ord for ImageDistEntry {
     }
 }
 
-pub fn calc_distance(image: &DynamicImage, h_entry: &ImageHashEntry) -> ImageDistEntry {
-    let hasher = mk_hasher(h_entry.hash_type);
-    let h: Hash = hasher.hash(&image).into();
-    let h_dist = h.dist(&h_entry.hash);
-
-    ImageDistEntry {
-        image_name: h_entry.image_name.clone(),
-        distance: h_dist,    
-    }
+pub fn calc_distance(image: &DynamicImage, weights: &HashWeights, config: &HasherConfig, h_entry: &ImageHashEntry) -> Option<ImageDistEntry> {
+    let hashes = calc_hashes(image, &weights.hash_types(), config);
+    calc_distance_from_hash(&hashes, weights, h_entry)
 }
 
-fn calc_distance_from_hash(hash: &Hash, h_entry: &ImageHashEntry) -> ImageDistEntry {
-    ImageDistEntry {
+fn calc_distance_from_hash(hashes: &BTreeMap<HashType, Hash>, weights: &HashWeights, h_entry: &ImageHashEntry) -> Option<ImageDistEntry> {
+    let (distance, norm_distance) = weights.fused_dist(hashes, &h_entry.hashes)?;
+    Some(ImageDistEntry {
         image_name: h_entry.image_name.clone(),
-        distance: hash.dist(&h_entry.hash),    
-    }
+        distance,    
+        norm_distance,
+    })
 }
 
-pub fn calc_similarity_list(image: &image::DynamicImage, hash_list: &Vec<ImageHashEntry>) -> Vec<ImageDistEntry> {
+/// Calculate fused distances between given image and every entry in `hash_list`.
+/// 
+/// The image is hashed once for every weighted hash type, with the same
+/// hasher config as the entries. Entries missing one of those hash types
+/// cannot be compared, and are skipped.
+pub fn calc_similarity_list(image: &image::DynamicImage, weights: &HashWeights, config: &HasherConfig, hash_list: &[ImageHashEntry]) -> Vec<ImageDistEntry> {
     
-    if hash_list.len() == 0 {
+    if hash_list.is_empty() {
         return vec![];
     }
     
-    // Important NOTE: we choose the first element from `hash_list`,
-    // and use it as the hasher for all element.
-    //
-    // It speeds up by ignore redundant hash calculation, but less
-    // generality, change if needed.
-    let hasher = mk_hasher(hash_list[0].hash_type);
-    let h: Hash = hasher.hash(&image).into();
-    
+    let hashes = calc_hashes(image, &weights.hash_types(), config);
+
+    calc_similarity_list_from_hash(&hashes, weights, hash_list)
+}
+
+/// Same as `calc_similarity_list`, with the image already hashed.
+pub fn calc_similarity_list_from_hash(hashes: &BTreeMap<HashType, Hash>, weights: &HashWeights, hash_list: &[ImageHashEntry]) -> Vec<ImageDistEntry> {
+    hash_list.iter()
+        .filter_map(|h_ent: &ImageHashEntry| {
+            calc_distance_from_hash(hashes, weights, h_ent)
+        }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_hash(bits: &[u8]) -> Hash {
+        Hash::from_bits(&bits.iter().map(|b| *b == 1).collect::<Vec<bool>>())
+    }
+
+    #[test]
+    fn test_fused_dist() {
+        let lhs: BTreeMap<HashType, Hash> = BTreeMap::from([
+            (HashType::PHASH, mk_hash(&[0, 0, 0, 0])),
+            (HashType::DHASH, mk_hash(&[1, 1, 1, 1])),
+        ]);
+        let rhs: BTreeMap<HashType, Hash> = BTreeMap::from([
+            (HashType::PHASH, mk_hash(&[1, 0, 0, 0])), // distance 1
+            (HashType::DHASH, mk_hash(&[0, 0, 0, 1])), // distance 3
+        ]);
+
+        // bounded by bit length
+        assert_eq!(lhs[&HashType::PHASH].max(), 4.0);
+        assert_eq!(lhs[&HashType::PHASH].norm_dist(&rhs[&HashType::DHASH]), 0.25);
+
+        let uniform = HashWeights::uniform(&[HashType::PHASH, HashType::DHASH]);
+        assert_eq!(uniform.fused_dist(&lhs, &rhs), Some((2.0, 0.5)));
+
+        let requested = HashMap::from([(HashType::PHASH, 3.0), (HashType::DHASH, 1.0)]);
+        let weighted = HashWeights::from_requested(&[HashType::PHASH, HashType::DHASH], &requested).unwrap();
+        assert_eq!(weighted.fused_dist(&lhs, &rhs), Some((1.5, 0.375)));
+
+        // a missing hash type cannot be fused
+        let ahash_only = HashWeights::uniform(&[HashType::AHASH]);
+        assert_eq!(ahash_only.fused_dist(&lhs, &rhs), None);
+    }
+
+    #[test]
+    fn test_search_limits() {
+        let dist_vec: Vec<ImageDistEntry> = [4.0, 1.0, 3.0, 2.0].iter()
+            .map(|&d| ImageDistEntry { 
+                image_name: PathBuf::from(format!("{}.png", d)), 
+                distance: d, 
+                norm_distance: d / 8.0 })
+            .collect();
+
+        let top_2 = SearchLimits { top_k: 2, max_distance: None, max_normalized_distance: None };
+        let picked: Vec<f64> = top_2.apply(dist_vec.clone()).iter().map(|x| x.distance).collect();
+        assert_eq!(picked, vec![1.0, 2.0]);
+
+        let under_3 = SearchLimits { top_k: 10, max_distance: Some(3.0), max_normalized_distance: None };
+        let picked: Vec<f64> = under_3.apply(dist_vec.clone()).iter().map(|x| x.distance).collect();
+        assert_eq!(picked, vec![1.0, 2.0, 3.0]);
+
+        let under_norm = SearchLimits { top_k: 10, max_distance: Some(3.0), max_normalized_distance: Some(0.25) };
+        let picked: Vec<f64> = under_norm.apply(dist_vec).iter().map(|x| x.distance).collect();
+        assert_eq!(picked, vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_pair_distances() {
+        let image = DynamicImage::ImageLuma8(image::GrayImage::from_fn(64, 64, |x, y| image::Luma([(x * 4 + y) as u8])));
+        let flipped = image.fliph();
+        let config = HasherConfig::default();
+
+        let same = calc_pair_distances(&image, &image, &HashType::ALL, &config);
+        assert_eq!(same.iter().map(|d| d.hash_type).collect::<Vec<_>>(), HashType::ALL);
+        assert!(same.iter().all(|d| d.distance == 0.0 && d.norm_distance == 0.0));
+
+        let apart = calc_pair_distances(&image, &flipped, &HashType::ALL, &config);
+        assert!(apart.iter().all(|d| d.distance > 0.0 && d.norm_distance <= 1.0));
+    }
+
+    #[test]
+    fn test_hasher_config() {
+        let image = DynamicImage::ImageLuma8(image::GrayImage::from_fn(64, 48, |x, y| {
+            image::Luma([((x * 7 + y * 13) % 256) as u8])
+        }));
+
+        // default config keeps the legacy cache extension
+        let default_config = HasherConfig::default();
+        assert_eq!(cache_ext(HashType::PHASH, &default_config), "phash");
+
+        let small_config = HasherConfig {
+            hash_width: 8,
+            hash_height: 8,
+            image_width: 8,
+            image_height: 8,
+            filter: ResizeFilter::Triangle,
+        };
+        assert!(small_config.validate(&[HashType::AHASH]).is_ok());
+        assert_eq!(cache_ext(HashType::AHASH, &small_config), "ahash-8x8-8x8-triangle");
+        assert_eq!(calc_hash(&image, HashType::AHASH, &small_config).bit_len(), 64);
+
+        let hashes_default = calc_hashes(&image, &[HashType::AHASH], &default_config);
+        assert_eq!(hashes_default[&HashType::AHASH].bit_len(), 1024);
+
+        // bit lengths are known without an image at hand
+        for hash_type in HashType::ALL {
+            for config in [&default_config, &small_config] {
+                assert_eq!(hash_bit_len(hash_type, config), calc_hash(&image, hash_type, config).bit_len());
+            }
+        }
+
+        let too_large = HasherConfig { hash_width: MAX_HASHER_SIZE + 1, ..HasherConfig::default() };
+        assert!(too_large.validate(&[HashType::AHASH]).is_err());
+
+        // the default config keeps its legacy sizes
+        assert!(default_config.validate(&HashType::ALL).is_ok());
+
+        // a hash larger than the image would be clipped
+        let wide_hash = HasherConfig { hash_width: 16, image_width: 8, image_height: 16, ..small_config.clone() };
+        assert!(wide_hash.validate(&[HashType::AHASH]).is_err());
+        let tall_hash = HasherConfig { hash_height: 16, image_width: 16, image_height: 8, ..small_config.clone() };
+        assert!(tall_hash.validate(&[HashType::AHASH]).is_err());
+
+        // dHash and pHash need one more column than the hash
+        assert!(small_config.validate(&[HashType::DHASH]).is_err());
+        assert!(small_config.validate(&[HashType::PHASH]).is_err());
+        let one_more_column = HasherConfig { image_width: 9, ..small_config.clone() };
+        for hash_type in [HashType::DHASH, HashType::PHASH] {
+            assert!(one_more_column.validate(&[hash_type]).is_ok());
+            assert_eq!(hash_bit_len(hash_type, &one_more_column), 64);
+        }
+
+        // aHash keeps a square of image_width, whatever the hash size
+        assert!(one_more_column.validate(&[HashType::AHASH]).is_err());
+        assert_eq!(hash_bit_len(HashType::AHASH, &one_more_column), 81);
+
+        // so no custom config suits aHash together with dHash or pHash
+        for config in [&small_config, &one_more_column] {
+            assert!(config.validate(&[HashType::AHASH, HashType::PHASH]).is_err());
+            assert!(config.validate(&[HashType::DHASH, HashType::AHASH]).is_err());
+        }
+        assert!(one_more_column.validate(&[HashType::DHASH, HashType::PHASH]).is_ok());
+    }
 
+    #[test]
+    fn test_legacy_cache_file() {
+        // cache files shipped from an older deployment, written before
+        // bits were packed.
+        let image_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("_app_from_container/test/S__18530317.jpg");
+        let config = HasherConfig::default();
 
-    hash_list.iter().map(|h_ent: &ImageHashEntry| {
-        calc_distance_from_hash(&h, &h_ent)
-    }).collect()
+        let cached = fetch_hash_cache(&image_path, HashType::PHASH, &config).unwrap();
+        assert_eq!(cached.bit_len(), 992);
+
+        let image = image::open(&image_path).unwrap();
+        assert_eq!(calc_hash(&image, HashType::PHASH, &config), cached);
+    }
+
+    #[test]
+    fn test_requested_weights() {
+        let available = [HashType::PHASH, HashType::DHASH];
+
+        // zero weights are dropped
+        let requested = HashMap::from([(HashType::PHASH, 1.0), (HashType::DHASH, 0.0)]);
+        let weights = HashWeights::from_requested(&available, &requested).unwrap();
+        assert_eq!(weights.hash_types(), vec![HashType::PHASH]);
+
+        // not indexed
+        let requested = HashMap::from([(HashType::AHASH, 1.0)]);
+        assert!(HashWeights::from_requested(&available, &requested).is_err());
+
+        // negative
+        let requested = HashMap::from([(HashType::PHASH, -1.0)]);
+        assert!(HashWeights::from_requested(&available, &requested).is_err());
+
+        // nothing positive
+        let requested = HashMap::from([(HashType::PHASH, 0.0)]);
+        assert!(HashWeights::from_requested(&available, &requested).is_err());
+    }
 }
\ No newline at end of file
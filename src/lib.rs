@@ -3,15 +3,18 @@ pub mod vec_ops;
 pub mod metric;
 pub mod image_hash;
 pub mod project_mgmt;
+pub mod project_db;
 mod utils;
 
-pub use utils::is_image_file;
+pub use utils::{is_image_file, has_image_extension, image_content_type};
 
 
+use std::collections::BTreeMap;
+
 use api::*;
 use image::DynamicImage;
 
-use crate::image_hash::ImageDistEntry;
+use crate::image_hash::{hash_bit_len, Hash, HashEncoding, HashType, HasherConfig, ImageDistEntry, ImageHashEntry};
 
 pub fn base64_to_image(base64_str: &str) 
     -> Result<image::DynamicImage, Box<dyn std::error::Error>> {
@@ -38,10 +41,10 @@ pub fn base64_to_image(base64_str: &str)
     Ok(img_decoded)
 }
 
-pub fn image_to_base64(image: &DynamicImage) 
-    -> Result<String, Box<dyn std::error::Error>> {
+/// Encode an image as PNG.
+pub fn image_to_png(image: &DynamicImage) 
+    -> Result<Vec<u8>, Box<dyn std::error::Error>> {
 
-    use base64::{engine::general_purpose, Engine};
     use std::io::Cursor;
 
     let mut image_data: Vec<u8> = Vec::new();
@@ -49,7 +52,32 @@ pub fn image_to_base64(image: &DynamicImage)
     image.write_to(
         &mut Cursor::new(&mut image_data), 
         image::ImageOutputFormat::Png)
-            .map_err(|_e| format!("base64 encode error: cannot write to intermediate buffer"))?;
+            .map_err(|_e| "png encode error: cannot write to intermediate buffer".to_string())?;
+
+    Ok(image_data)
+}
+
+/// Encode an image in the format of its file name, as saving it there
+/// would.
+pub fn image_to_file_bytes(image: &DynamicImage, image_path: &std::path::Path) 
+    -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+
+    use std::io::Cursor;
+
+    let format = image::ImageFormat::from_path(image_path)?;
+    let mut image_data: Vec<u8> = Vec::new();
+    image.write_to(&mut Cursor::new(&mut image_data), format)?;
+
+    Ok(image_data)
+}
+
+pub fn image_to_base64(image: &DynamicImage) 
+    -> Result<String, Box<dyn std::error::Error>> {
+
+    use base64::{engine::general_purpose, Engine};
+
+    let image_data = image_to_png(image)
+        .map_err(|_e| "base64 encode error: cannot write to intermediate buffer".to_string())?;
 
     let b64_str = general_purpose::STANDARD.encode(image_data);
 
@@ -68,14 +96,32 @@ impl HasSingleImage for UploadImageReq {
     }
 }
 
+impl HasSingleImage for BatchImage {
+    fn get_image(&self) -> Result<image::DynamicImage, Box<dyn std::error::Error>> {
+        base64_to_image(&self.data)
+    }
+}
+
+impl HasSingleImage for CompareQuery {
+    fn get_image(&self) -> Result<image::DynamicImage, Box<dyn std::error::Error>> {
+        base64_to_image(&self.data)
+    }
+}
+
+impl HasSingleImage for PairImage {
+    fn get_image(&self) -> Result<image::DynamicImage, Box<dyn std::error::Error>> {
+        base64_to_image(&self.data)
+    }
+}
+
 impl HasSingleImage for CompareImageReq {
     fn get_image(&self) -> Result<image::DynamicImage, Box<dyn std::error::Error>> {
         base64_to_image(&self.data)
     }
 }
 
-/// Convert a`ImageDistEntry` to `SimilarImageEntry`.
-pub fn dist_entry_to_api_sim_entry(dist: &ImageDistEntry, with_image: bool)
+/// Convert a`ImageDistEntry` of given project to `SimilarImageEntry`.
+pub fn dist_entry_to_api_sim_entry(dist: &ImageDistEntry, project_name: &str, with_image: bool)
     -> SimilarImageEntry {
 
     let image_data = match with_image {
@@ -97,10 +143,53 @@ pub fn dist_entry_to_api_sim_entry(dist: &ImageDistEntry, with_image: bool)
 
     SimilarImageEntry { 
         image_name, 
+        project_name: project_name.to_owned(),
         distance: dist.distance as f32, 
+        similarity: (1.0 - dist.norm_distance) as f32,
         data: image_data }
 }
 
+/// Convert a `ImageHashEntry` to `ImageHashInfo`, with hashes encoded as text.
+pub fn hash_entry_to_api_hash_info(entry: &ImageHashEntry, encoding: HashEncoding) -> ImageHashInfo {
+    let image_name = match entry.image_name.file_name() {
+        None => "".to_owned(),
+        Some(f) => f.to_string_lossy().into_owned(),
+    };
+
+    ImageHashInfo {
+        image_name,
+        hashes: entry.hashes.iter().map(|(t, h)| (*t, h.encode(encoding))).collect(),
+    }
+}
+
+/// Decode the hashes of a hash query, for a project hashed with `config`
+/// and `hash_types`.
+/// 
+/// Hashes are only comparable under the same hasher config, and their
+/// bit lengths follow from it.
+pub fn decode_query_hash(query: &QueryHash, config: &HasherConfig, hash_types: &[HashType])
+    -> Result<BTreeMap<HashType, Hash>, Box<dyn std::error::Error>> {
+
+    let query_config = query.hasher_config.clone().unwrap_or_default();
+    if query_config != *config {
+        return Err(format!("hashes were computed with {:?}, but the project is hashed with {:?}",
+                            query_config, config).into());
+    }
+    if query.hashes.is_empty() {
+        return Err("query_hash holds no hash".into());
+    }
+
+    query.hashes.iter()
+        .map(|(hash_type, text)| {
+            if !hash_types.contains(hash_type) {
+                return Err(format!("hash type {:?} is not indexed, available: {:?}", hash_type, hash_types).into());
+            }
+            let hash = Hash::decode(text, query.encoding, hash_bit_len(*hash_type, config))
+                .map_err(|e| format!("invalid {:?} hash: {}", hash_type, e))?;
+            Ok((*hash_type, hash))
+        })
+        .collect()
+}
 
 
 #[cfg(test)]
@@ -119,7 +208,26 @@ mod tests {
         assert_eq!((8, 7), (im1_.width(), im1_.height()));
 
         assert_eq!(im1_, base64_to_image(image_to_base64(&im1_).unwrap().as_str()).unwrap());
+    }
 
-        ()
+    #[test]
+    fn test_decode_query_hash() {
+        let config = HasherConfig::default();
+        let image = base64_to_image("iVBORw0KGgoAAAANSUhEUgAAAAgAAAAHCAIAAAC6O5sJAAAAGUlEQVR4nGJh+jWFARtgwio60BKAAAAA//8VUgGhHLHyHAAAAABJRU5ErkJggg==").unwrap();
+        let hashes = image_hash::calc_hashes(&image, &[HashType::PHASH], &config);
+
+        let mut query = QueryHash {
+            hashes: [(HashType::PHASH, hashes[&HashType::PHASH].encode(HashEncoding::Base64))].into(),
+            encoding: HashEncoding::Base64,
+            hasher_config: None,
+        };
+        assert_eq!(decode_query_hash(&query, &config, &[HashType::PHASH]).unwrap(), hashes);
+
+        // hash type the project does not have
+        assert!(decode_query_hash(&query, &config, &[HashType::DHASH]).is_err());
+
+        // hashes of another config cannot be compared
+        query.hasher_config = Some(HasherConfig { hash_width: 8, hash_height: 8, ..config.clone() });
+        assert!(decode_query_hash(&query, &config, &[HashType::PHASH]).is_err());
     }
 }
\ No newline at end of file
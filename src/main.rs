@@ -2,21 +2,21 @@
 use regex::Regex;
 use std::cmp::min;
 use std::error::Error;          // standard error trait
-use std::time::Instant;         // calculate time difference
-use std::collections::HashMap;  // hashmap support
+use std::time::{Instant, UNIX_EPOCH}; // calculate time difference
+use std::collections::{BTreeMap, HashMap, HashSet};  // hashmap support
 use image::DynamicImage;        // image IO
-use itertools::Itertools;       // functional pattern support to make life easier
+use itertools::{Either, Itertools}; // functional pattern support to make life easier
 
 // asynchronous execution and management
 use tokio::sync::RwLock;    // shared object management
 use std::sync::Arc;         // shared object reference
 
 // HTTP related libs
-use axum::http::{Response, StatusCode, HeaderValue, Method}; // HTTP
+use axum::http::{Response, StatusCode, Method}; // HTTP
 use axum::response::IntoResponse;       // convert to response
-use axum::routing::{post, delete};     // HTTP methods
+use axum::routing::{get, post, delete}; // HTTP methods
 use axum::body::Body;                   // plain response body
-use axum::extract::{Json, State, Path as PathParam}; // response types
+use axum::extract::{DefaultBodyLimit, FromRef, Json, Query, State, Path as PathParam}; // response types
 use axum::{Router, http};               // router
 use tokio::net::TcpListener;            // listener
 use std::net::SocketAddr;               // socker definition
@@ -24,30 +24,208 @@ use tower_http::cors::{CorsLayer, Any}; // CORS support
 
 // filesystem and os-related libraries
 use std::path::{Path, PathBuf, Component};      // filesystem path operations
-use std::fs::{read_dir, create_dir, remove_dir_all}; // filesystem utils
+use std::fs::{read_dir, create_dir, remove_dir_all, remove_file}; // filesystem utils
 
 // internal libraries
 use vismatch_svc::{
-    HasSingleImage,         // trait for getting image from request object
-    base64_to_image, 
+    image_to_png,
+    has_image_extension,
+    decode_query_hash,
+    hash_entry_to_api_hash_info,
+    image_content_type,
     dist_entry_to_api_sim_entry, image_hash::*};     // our packaged hash algorithms
 
 use vismatch_svc::project_mgmt::{
-    load_or_calc_project_hashes     
+    Project,
+    ProjectManifest,
+    ProjectState,
+    HashProgress,
+    load_projects,
+    hash_project_images,
+    list_project_images,
+    stage_project_images,
+    stage_project_image,
+    StagedImage,
+    project_disk_usage,
+    load_project_manifest,
+    load_or_init_project_manifest,
+    write_project_manifest,
+    ProjectTokens,
+    duplicate_clusters,
 };
+use vismatch_svc::project_db::ProjectDb;
 use vismatch_svc::api::*;           // API structure
 
+mod watcher;    // keeps projects in sync with their folders
 
-type ProjectHashDict = Arc<RwLock<HashMap<String, Vec<ImageHashEntry>>>>;
+
+type ProjectHashDict = Arc<RwLock<HashMap<String, ProjectState>>>;
+
+/// Where a deletion token points to.
+#[derive(Debug, Clone)]
+struct ImageToken {
+    project_name: String,
+    image_name: String,
+}
+
+/// Deletion tokens of every project, by project name.
+///
+/// [NOTE] lock order: always take `ProjectHashDict` first, then `TokenDict`.
+type TokenDict = Arc<RwLock<HashMap<String, ProjectTokens>>>;
+
+/// Environment variable which sets how many images are hashed at once
+/// when loading projects. Defaults to the number of CPUs.
+const HASH_WORKERS_ENV: &str = "HASH_WORKERS";
+
+/// Environment variable which turns the project folder watcher off when
+/// set to `0`, `false` or `off`. The watcher is on by default.
+const WATCH_PROJECTS_ENV: &str = "WATCH_PROJECTS";
+
+/// Environment variable which sets the largest accepted request body in
+/// MiB. Defaults to `DEFAULT_MAX_BODY_MB`.
+const MAX_BODY_MB_ENV: &str = "MAX_BODY_MB";
+
+/// Largest accepted request body in MiB when not set, enough for a 20 MB
+/// scan sent as base64 JSON.
+const DEFAULT_MAX_BODY_MB: usize = 64;
+
+/// Number of closest images returned by `/diff` when not asked.
+const DEFAULT_TOP_K: usize = 3;
+
+/// Upper bound of images returned by `/diff`.
+const MAX_TOP_K: usize = 1000;
+
+/// Images per page of a project image listing when not asked.
+const DEFAULT_PAGE_SIZE: usize = 50;
+
+/// Upper bound of images per page of a project image listing.
+const MAX_PAGE_SIZE: usize = 500;
+
+/// Longest side of thumbnails in pixels when not asked.
+const DEFAULT_THUMBNAIL_SIZE: u32 = 256;
+
+/// Upper bound of the longest side of thumbnails in pixels.
+const MAX_THUMBNAIL_SIZE: u32 = 1024;
+
+/// Project name searching every project in `/diff`.
+const ALL_PROJECTS: &str = "*";
+
+/// Largest normalized distance of an upload to an indexed image for them
+/// to be near-duplicates, when not asked.
+const DEFAULT_DUPLICATE_THRESHOLD: f32 = 0.05;
+/// Largest threshold of the duplicate report, larger ones link most of a project.
+const MAX_DUPLICATE_THRESHOLD: f32 = 0.25;
+/// Most pairs of near-duplicates a duplicate report searches for.
+const MAX_DUPLICATE_PAIRS: usize = 10_000;
+
+/// Longest accepted image name, in bytes.
+const MAX_IMAGE_NAME_LEN: usize = 255;
+
+/// Upper bound of images in one batch request.
+const MAX_BATCH_IMAGES: usize = 500;
+
+/// Upper bound of images returned by `/diff` with `with_image`, since every
+/// one of them is re-encoded into the response.
+const MAX_TOP_K_WITH_IMAGE: usize = 50;
 
 #[derive(Clone)]
 struct AppState {
     project_root: String,
     project_dict: ProjectHashDict,
+    token_dict: TokenDict,
+    body_limit: BodyLimit,
+}
+
+impl FromRef<AppState> for BodyLimit {
+    fn from_ref(state: &AppState) -> Self {
+        state.body_limit
+    }
 }
 
 // common task definition
 
+/// Check that a project can serve requests.
+/// 
+/// Unknown projects pass, so callers keep reporting them their own way.
+fn check_project_ready(project_dict: &HashMap<String, ProjectState>, project_name: &str) -> Result<(), AppError> {
+    match project_dict.get(project_name) {
+        None | Some(ProjectState::Ready(_)) => Ok(()),
+        Some(ProjectState::Loading(progress)) => {
+            let progress = LoadProgress { done: progress.done(), total: progress.total() };
+            Err(AppError::ServiceUnavailable(
+                format!("project <{}> is loading ({}/{} images), please retry later", 
+                    project_name, progress.done, progress.total),
+                Some(progress)))
+        },
+        Some(ProjectState::Failed(err)) => Err(AppError::InternalError(
+            format!("project <{}> failed to load: {}", project_name, err))),
+    }
+}
+
+/// Replace the loading state of a project by its loaded one.
+///
+/// The project may be deleted or re-created while loading, so the state
+/// is only replaced if it still belongs to the same load.
+fn publish_loaded_project(
+    project_dict: &ProjectHashDict,
+    project_name: &str,
+    progress: &Arc<HashProgress>,
+    project: Result<Project, Box<dyn Error>>) {
+
+    let project_state = match project {
+        Ok(project) => ProjectState::Ready(Box::new(project)),
+        Err(err) => {
+            println!("[!] cannot load project <{}>: {}", project_name, err);
+            ProjectState::Failed(err.to_string())
+        },
+    };
+
+    let mut project_dict_wlock = project_dict.blocking_write();
+    if let Some(ProjectState::Loading(p)) = project_dict_wlock.get(project_name)
+        && Arc::ptr_eq(p, progress) {
+        project_dict_wlock.insert(project_name.to_owned(), project_state);
+    }
+}
+
+/// Number of hashing workers, from `HASH_WORKERS_ENV` or the number of CPUs.
+fn hash_workers() -> usize {
+    let default_workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+    match std::env::var(HASH_WORKERS_ENV) {
+        Err(_) => default_workers,
+        Ok(v) => match v.trim().parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                println!("[!] invalid {}={:?}, using {} workers", HASH_WORKERS_ENV, v, default_workers);
+                default_workers
+            },
+        },
+    }
+}
+
+/// Largest accepted request body, from `MAX_BODY_MB_ENV`.
+fn body_limit() -> BodyLimit {
+    let max_body_mb = match std::env::var(MAX_BODY_MB_ENV) {
+        Err(_) => DEFAULT_MAX_BODY_MB,
+        Ok(v) => match v.trim().parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                println!("[!] invalid {}={:?}, using {} MiB", MAX_BODY_MB_ENV, v, DEFAULT_MAX_BODY_MB);
+                DEFAULT_MAX_BODY_MB
+            },
+        },
+    };
+    BodyLimit(max_body_mb * 1024 * 1024)
+}
+
+/// Whether project folders are watched, from `WATCH_PROJECTS_ENV`.
+fn watch_projects() -> bool {
+    match std::env::var(WATCH_PROJECTS_ENV) {
+        Err(_) => true,
+        Ok(v) => !matches!(v.trim().to_lowercase().as_str(), "0" | "false" | "off"),
+    }
+}
+
 fn validate_project_name(name: &str) -> Result<(), AppError> {
     let name = name.trim();
 
@@ -76,199 +254,1149 @@ fn validate_project_name(name: &str) -> Result<(), AppError> {
 
     Ok(())
 }
+
+/// Check an image name given by a client: a plain file name with an
+/// image extension, so the image stays right in its project folder.
+/// Hidden names are refused, they hold the project data.
+fn validate_image_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("image_name cannot be empty".to_owned());
+    }
+    if name.len() > MAX_IMAGE_NAME_LEN {
+        return Err("image_name too long".to_owned());
+    }
+    let mut comps = Path::new(name).components();
+    let is_plain = matches!((comps.next(), comps.next()), (Some(Component::Normal(_)), None))
+        && !name.contains(['/', '\\'])
+        && !name.chars().any(char::is_control);
+    if !is_plain {
+        return Err("invalid image_name, it must be a plain file name".to_owned());
+    }
+    if name.starts_with('.') {
+        return Err("image_name cannot start with '.'".to_owned());
+    }
+
+    if !has_image_extension(Path::new(name)) {
+        return Err("image_name must have an image extension, like .png or .jpg".to_owned());
+    }
+
+    Ok(())
+}
+
+/// Name an uploaded image is stored under in its project folder.
+/// 
+/// A name is taken if a file of that name is in the folder, or if it is
+/// `reserved` for another upload which is not stored yet. Taken names are
+/// handled by the collision policy, while reserved ones are never
+/// overwritten. An error tells why the image cannot be stored.
+/// 
+/// Returns the name, and whether it replaces an image in the folder.
+fn resolve_image_name(project_path: &Path, image_name: &str, on_collision: CollisionPolicy, reserved: &HashSet<String>)
+    -> Result<(String, bool), String> {
+
+    let is_taken = |name: &str| reserved.contains(name) || project_path.join(name).exists();
+
+    if !is_taken(image_name) {
+        return Ok((image_name.to_owned(), false));
+    }
+    if reserved.contains(image_name) && on_collision != CollisionPolicy::Rename {
+        return Err(format!("image <{}> is being uploaded, retry later", image_name));
+    }
+
+    match on_collision {
+        CollisionPolicy::Reject => Err(format!("image <{}> exists already", image_name)),
+        CollisionPolicy::Overwrite => Ok((image_name.to_owned(), true)),
+        CollisionPolicy::Rename => {
+            let image_path = Path::new(image_name);
+            let stem = image_path.file_stem().unwrap_or_default().to_string_lossy();
+            let ext = image_path.extension().unwrap_or_default().to_string_lossy();
+
+            let free_name = (1..)
+                .map(|n| format!("{}_{}.{}", stem, n, ext))
+                .find(|name| !is_taken(name))
+                .unwrap_or_default();
+            Ok((free_name, false))
+        },
+    }
+}
+
+/// Give back names reserved for images of a project, once they are stored
+/// or failed to.
+fn release_image_names(project_dict: &mut HashMap<String, ProjectState>, project_name: &str, image_names: &[String]) {
+    if let Some(project) = project_dict.get_mut(project_name).and_then(ProjectState::ready_mut) {
+        for image_name in image_names {
+            project.reserved_names.remove(image_name);
+        }
+    }
+}
+
+/// Create project folder and manifest, and register the empty project
+/// in hash dict.
+fn create_project(
+    project_path: &Path,
+    project_name: &str,
+    manifest: ProjectManifest,
+    project_dict: &mut HashMap<String, ProjectState>) -> Result<ProjectManifest, Box<dyn Error + Send + Sync>> {
+
+    create_dir(project_path)
+        .map_err(|e| format!("cannot create project folder: {}", e))?;
+
+    write_project_manifest(project_path, &manifest)
+        .map_err(|e| format!("cannot write project manifest: {}", e))?;
+
+    let (db, _) = ProjectDb::open(project_path)
+        .map_err(|e| format!("cannot create project index: {}", e))?;
+
+    project_dict.insert(project_name.to_owned(), ProjectState::Ready(Box::new(Project::new(manifest.clone(), db))));
+
+    println!("[*] created project <{}> ({:?})", project_name, manifest.hash_types());
+
+    Ok(manifest)
+}
+
+/// Store an uploaded image in its project and index it.
+/// 
+/// The image is hashed as it is stored, encoded in the format of its
+/// name, without holding the project lock. Its file is only written once
+/// the near-duplicate check lets it in.
 async fn save_image_to_project(
     project_root: &str,
-    project_name: &str, 
-    image: &DynamicImage, 
-    image_name: &str,
-    hash_type: HashType,
-    project_hashes: ProjectHashDict) -> Result<(), Box<dyn Error + Send + Sync>> {
+    request: &UploadImageReq,
+    image: DynamicImage, 
+    project_hashes: ProjectHashDict,
+    tokens: TokenDict) -> Result<UploadImageResp, AppError> {
+
+    let project_name = request.project_name.as_str();
+    let internal_error = |e: String| AppError::InternalError(e);
 
     let project_root = Path::new(project_root);
     let project_path = &project_root.join(project_name);
 
-    let _project_hashes = Arc::clone(&project_hashes);
-    let mut project_dict_wlock = _project_hashes.write().await;
-
-    // check project dir
-    match project_path.is_dir() {
-        false => {
-            // create project folder
-            create_dir(project_path)
-                .map_err(|e| format!("cannot create project folder: {}", e.to_string()))?;
-
-            // create entry for our new project.
-            (*project_dict_wlock).insert(project_name.to_owned(), Vec::<ImageHashEntry>::new());
+    // images are always hashed with the hash types and config of their
+    // project, and a taken name is handled as asked. The name is reserved
+    // until the image is stored, so no other upload takes it meanwhile.
+    let (manifest, image_name, is_overwrite) = {
+        let mut project_dict_wlock = project_hashes.write().await;
+
+        let manifest = ensure_project(project_path, project_name, request.hash_type, &mut project_dict_wlock)
+            .map_err(|e| internal_error(e.to_string()))?;
+        let project = project_dict_wlock.get_mut(project_name).and_then(ProjectState::ready_mut)
+            .ok_or_else(|| internal_error(format!("project <{}> not found in current database", project_name)))?;
+
+        let (image_name, is_overwrite) = resolve_image_name(
+            project_path, &request.image_name, request.on_collision, &project.reserved_names)
+            .map_err(AppError::Conflict)?;
+        project.reserved_names.insert(image_name.clone());
+        (manifest, image_name, is_overwrite)
+    };
+    let image_target_path = project_path.join(&image_name);
+
+    // encode, hash and stage the image, this involves image resizing and
+    // writing, which are blocking tasks. So we put it in seprated thread.
+    let _image_target_path = image_target_path.clone();
+    let staged = tokio::task::spawn_blocking(move || {
+        stage_project_image(&_image_target_path, &image, &manifest).map_err(|e| e.to_string())
+    }).await
+        .map_err(|e| internal_error(e.to_string()))
+        .and_then(|staged| staged.map_err(internal_error));
+
+    // the near-duplicate check and the index update happen under one lock,
+    // so near-duplicates uploaded together are still caught. The name is
+    // given back then, the image is stored or not once the lock is released.
+    let mut project_dict_wlock = project_hashes.write().await;
+    release_image_names(&mut project_dict_wlock, project_name, std::slice::from_ref(&image_name));
+    let staged = staged?;
+
+    let Some(project) = project_dict_wlock.get_mut(project_name).and_then(ProjectState::ready_mut) else {
+        staged.discard();
+        return Err(internal_error(format!("project <{}> not found in current database", project_name)));
+    };
+
+    // a near-duplicate of an indexed image is handled as asked. The image
+    // being overwritten does not count.
+    let duplicate_of = request.on_duplicate
+        .and_then(|_| {
+            let threshold = request.duplicate_threshold.unwrap_or(DEFAULT_DUPLICATE_THRESHOLD) as f64;
+            project.find_duplicate(&staged.entry.hashes, threshold, Some(&image_target_path))
+        })
+        .map(|entry| dist_entry_to_api_sim_entry(&entry, project_name, false));
+
+    if let (Some(policy), Some(duplicate)) = (request.on_duplicate, &duplicate_of) {
+        let found = format!("image is a near-duplicate of <{}> (distance {})", duplicate.image_name, duplicate.distance);
+        match policy {
+            DuplicatePolicy::Reject => {
+                staged.discard();
+                return Err(AppError::Conflict(found));
+            },
+            DuplicatePolicy::Link => {
+                staged.discard();
+                println!("[*] {}, linked instead of stored", found);
+                return Ok(UploadImageResp {
+                    success: true,
+                    message: format!("{}, linked to it instead of stored", found),
+                    token: String::new(),
+                    image_name: duplicate.image_name.clone(),
+                    duplicate_of,
+                });
+            },
+            DuplicatePolicy::Store => {},
         }
-        true => {} // continue execution
     }
 
-    // now add image name
-    let image_target_path = project_path.join(image_name);
-
     // [NOTE] verbose print
     println!("[*] saving image to <{}>", image_target_path.to_string_lossy());
 
-    // save the image
-    image.save(&image_target_path)
-        .map_err(|e: image::ImageError| 
-            Box::<dyn std::error::Error + Send + Sync>::from(   // I know it's tricky, but we need to cast the error
-                format!("error while saving image: {}", e.to_string())))?;
+    // now we can move the image into place and update the project index,
+    // an image of the same name is replaced. Nothing is left on disk if
+    // it fails.
+    project.put_staged_images(vec![staged])
+        .map_err(|e| internal_error(e.to_string()))?
+        .remove(0)
+        .map_err(internal_error)?;
+
+    // finally, mint a deletion token for the stored image and persist it.
+    // tokens of an overwritten image do not delete the new one. Tokens are
+    // locked before the project is released, so uploads of the same name
+    // mint in the order they stored.
+    let mut token_dict_wlock = tokens.write().await;
+    drop(project_dict_wlock);
+    if is_overwrite {
+        forget_image_tokens(&mut token_dict_wlock, project_path, project_name, std::slice::from_ref(&image_name))
+            .map_err(|e| internal_error(format!("cannot persist deletion tokens: {}", e)))?;
+    }
+    let token = mint_deletion_tokens(&mut token_dict_wlock, project_path, project_name, std::slice::from_ref(&image_name))
+        .map_err(|e| internal_error(format!("cannot persist deletion token: {}", e)))?
+        .remove(0);
 
-    // now we need to calculate, and update the global hash dict.
-    // we clone this, since it will be moved to other thread
-    let _image_target_path = image_target_path.clone();
+    let message = match &duplicate_of {
+        None => "image uploaded and indexed successfully".to_owned(),
+        Some(duplicate) => format!("image uploaded and indexed successfully, near-duplicate of <{}>", duplicate.image_name),
+    };
 
-    // we spawn a task to calculate hash.
-    let hash_calc_task = 
-        tokio::task::spawn_blocking(move || {    
-            let image_target_path = _image_target_path;
-
-            // we need type annotation, so we created a new varibale here to hold result.
-            let res: Result<ImageHashEntry, Box<dyn Error + Send + Sync>> = 
-                fetch_cache_or_calc_hash(
-                    &image_target_path, 
-                    hash_type,
-                    true)
-                    .map_err(|f|f.to_string().into());  
-            res // return the result
-        });
+    Ok(UploadImageResp {
+        success: true,
+        message,
+        token,
+        image_name,
+        duplicate_of,
+    }) // All good, return
+}
 
-    let hash_result: ImageHashEntry = hash_calc_task.await??; // now we have the calculated hash.
+/// Manifest of a ready project, creating the project with given hash type
+/// if its folder does not exist.
+fn ensure_project(
+    project_path: &Path,
+    project_name: &str,
+    hash_type: Option<HashType>,
+    project_dict: &mut HashMap<String, ProjectState>) -> Result<ProjectManifest, Box<dyn Error + Send + Sync>> {
+
+    if !project_path.is_dir() {
+        let manifest = ProjectManifest {
+            hash_type: hash_type.unwrap_or(ProjectManifest::default().hash_type),
+            ..ProjectManifest::default()
+        };
+        create_project(project_path, project_name, manifest, project_dict)?;
+    }
 
-    // now we can update the project hash dict.
-    let project_name = project_name;
+    match project_dict.get(project_name).and_then(ProjectState::ready) {
+        Some(project) => Ok(project.manifest.clone()),
+        None => Err(format!("project <{}> not found in current database", project_name).into()),
+    }
+}
 
-    if let Some(val) = 
-        (*project_dict_wlock).get_mut(project_name) { 
-            val.push(hash_result); 
+/// Tokens of a project, loaded on first use.
+fn tokens_of<'a>(
+    token_dict: &'a mut HashMap<String, ProjectTokens>,
+    project_path: &Path,
+    project_name: &str) -> Result<&'a mut ProjectTokens, Box<dyn Error>> {
+
+    if !token_dict.contains_key(project_name) {
+        token_dict.insert(project_name.to_owned(), ProjectTokens::open(project_path)?);
     }
+    Ok(token_dict.get_mut(project_name).expect("tokens are loaded"))
+}
+
+/// Where a deletion token points to, if it is known.
+fn find_image_token(token_dict: &HashMap<String, ProjectTokens>, token: &str) -> Option<ImageToken> {
+    token_dict.iter().find_map(|(project_name, tokens)| tokens.image_of(token).map(|image_name| ImageToken {
+        project_name: project_name.clone(),
+        image_name: image_name.to_owned(),
+    }))
+}
 
-    Ok(()) // All good, return
+/// Mint a deletion token per stored image, and persist them. Returns the
+/// tokens in the order of `image_names`.
+fn mint_deletion_tokens(
+    token_dict: &mut HashMap<String, ProjectTokens>,
+    project_path: &Path,
+    project_name: &str,
+    image_names: &[String]) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+
+    tokens_of(token_dict, project_path, project_name)
+        .and_then(|tokens| tokens.mint(image_names))
+        .map_err(|e| e.to_string().into())
 }
 
+/// Drop deletion tokens pointing to images which are gone, and persist the
+/// change.
+fn forget_image_tokens(
+    token_dict: &mut HashMap<String, ProjectTokens>,
+    project_path: &Path,
+    project_name: &str,
+    image_names: &[String]) -> Result<(), Box<dyn Error>> {
+
+    if image_names.is_empty() {
+        return Ok(());
+    }
+    tokens_of(token_dict, project_path, project_name)?.forget_images(image_names)
+}
 
-/// For a given image and specified project name, calculate
-/// the difference list across project images for provided image.
-async fn calc_sim_in_project(image: DynamicImage, project_name: &str, project_hashes: ProjectHashDict) 
-    -> Result<Vec<ImageDistEntry>, Box<dyn Error + Send + Sync>>{
+/// Remove the image pointed by given token, together with its entries in
+/// project index and hash dict.
+///
+/// Returns `Ok(None)` if the token is unknown.
+async fn remove_image_from_project(
+    project_root: &str,
+    token: &str,
+    project_hashes: ProjectHashDict,
+    tokens: TokenDict) -> Result<Option<ImageToken>, Box<dyn Error + Send + Sync>> {
+
+    let image_token = match find_image_token(&*tokens.read().await, token) {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+
+    let project_path = Path::new(project_root).join(&image_token.project_name);
+    let image_target_path = project_path.join(&image_token.image_name);
+
+    let mut project_dict_wlock = project_hashes.write().await;
+
+    // drop the indexed entries first.
+    if let Some(project) = (*project_dict_wlock).get_mut(&image_token.project_name).and_then(ProjectState::ready_mut) {
+        project.remove_image(&image_target_path).map_err(|e| e.to_string())?;
+    }
+
+    // [NOTE] verbose print
+    println!("[*] removing image <{}>", image_target_path.to_string_lossy());
+
+    // then the files on disk, a missing image is not an error here.
+    match remove_file(&image_target_path) {
+        Ok(_) => {},
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+        Err(e) => return Err(format!("cannot remove image file: {}", e).into()),
+    }
+
+    // finally forget the token.
+    let mut token_dict_wlock = tokens.write().await;
+    tokens_of(&mut token_dict_wlock, &project_path, &image_token.project_name)
+        .and_then(|project_tokens| project_tokens.remove(&[token.to_owned()]))
+        .map_err(|e| format!("cannot persist deletion tokens: {}", e))?;
+
+    Ok(Some(image_token))
+}
+
+
+/// Weights of a search in a project indexing `hash_types`, equal weights
+/// if the request gives none.
+fn weights_of(hash_types: &[HashType], weights: Option<&HashMap<HashType, f32>>) -> Result<HashWeights, String> {
+    match weights {
+        None => Ok(HashWeights::uniform(hash_types)),
+        Some(w) => HashWeights::from_requested(hash_types, w).map_err(|e| e.to_string()),
+    }
+}
+
+/// For a given image and several projects, calculate the difference list
+/// across images of every project.
+/// 
+/// Distances of every hash type of a project are fused with `weights`,
+/// or with equal weights if not given. Only the closest images within
+/// `limits` are returned, closest first.
+/// 
+/// The image is hashed once per hasher config in use, not once per
+/// project. Returns the closest images of every project, in order.
+async fn calc_sim_in_projects(
+    image: DynamicImage, 
+    project_names: &[String], 
+    weights: Option<&HashMap<HashType, f32>>,
+    limits: SearchLimits,
+    project_hashes: ProjectHashDict) 
+    -> Result<Vec<Vec<ImageDistEntry>>, Box<dyn Error + Send + Sync>>{
+
+    let calc_start = Instant::now(); // Measure calc time
+
+    let not_found = |project_name: &str| format!("project <{}> not found in current database", project_name);
+
+    // first, how every project is hashed: the hash types each config needs,
+    // and the config and weights of every project.
+    let mut configs: Vec<(HasherConfig, Vec<HashType>)> = vec![];
+    let mut searches: Vec<(usize, HashWeights)> = vec![];
+    for project_name in project_names.iter() {
+        let project_dict_rlock = project_hashes.read().await;
+        let project = project_dict_rlock.get(project_name).and_then(ProjectState::ready)
+            .ok_or_else(|| not_found(project_name))?;
+
+        let project_weights = weights_of(&project.manifest.hash_types(), weights)?;
+        let config = &project.manifest.hasher_config;
+
+        let slot = match configs.iter().position(|(c, _)| c == config) {
+            Some(slot) => slot,
+            None => {
+                configs.push((config.clone(), vec![]));
+                configs.len() - 1
+            },
+        };
+        for hash_type in project_weights.hash_types() {
+            if !configs[slot].1.contains(&hash_type) {
+                configs[slot].1.push(hash_type);
+            }
+        }
+        searches.push((slot, project_weights));
+    }
+
+    // Hashing involves image resizing, which is a cpu task.
+    // So we put it in seprated thread. 
+    let hashed: Vec<BTreeMap<HashType, Hash>> = tokio::task::spawn_blocking(move || {
+        configs.iter()
+            .map(|(config, hash_types)| calc_hashes(&image, hash_types, config))
+            .collect()
+    }).await?;
+
+    // then look the closest images up in every project index.
+    let project_dict_rlock = project_hashes.read().await;
+    let diff_results = project_names.iter().zip(searches.iter())
+        .map(|(project_name, (slot, project_weights))| {
+            let project = project_dict_rlock.get(project_name).and_then(ProjectState::ready)
+                .ok_or_else(|| not_found(project_name))?;
+            Ok(project.index.search(&hashed[*slot], project_weights, &limits))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    println!("[*] calculation task done: {:.3?}", calc_start.elapsed());
+
+    Ok(diff_results)
+}
+
+/// Look the closest images of several query images up in a project.
+/// 
+/// The `i`-th query is decoded by `load(i)`, and searched with `limits[i]`.
+/// Queries are decoded and hashed in parallel, then all of them are
+/// searched under a single read of the project.
+/// 
+/// Returns the closest images of every query in order, or why the query
+/// failed.
+async fn calc_sim_in_project_batch<F>(
+    load: F,
+    limits: Vec<SearchLimits>,
+    project_name: &str, 
+    weights: Option<&HashMap<HashType, f32>>,
+    project_hashes: ProjectHashDict) 
+    -> Result<Vec<Result<Vec<ImageDistEntry>, String>>, Box<dyn Error + Send + Sync>>
+where
+    F: Fn(usize) -> Result<DynamicImage, Box<dyn Error>> + Send + Sync + 'static,
+{
     // println!("[*] enter calculation blk");
 
     let calc_start = Instant::now(); // Measure calc time
 
-    let image = image.clone();
+    let not_found = || format!("project <{}> not found in current database", project_name);
+
+    // first, we should check if the project exists, and how it is hashed.
+    let (weights, hasher_config) = match project_hashes.read().await.get(project_name).and_then(ProjectState::ready) {
+        Some(project) => {
+            let weights = weights_of(&project.manifest.hash_types(), weights)?;
+            (weights, project.manifest.hasher_config.clone())
+        },
+        None => return Err(not_found().into()),
+    };
+
+    // Hashing involves image resizing, which is a cpu task.
+    // So we put it in seprated thread. 
+    let hash_types = weights.hash_types();
+    let count = limits.len();
+    let hash_calc_task = 
+        tokio::task::spawn_blocking(move || {            
+            calc_hashes_batch(count, &hash_types, &hasher_config, hash_workers(), load)
+        });
+
+    let hashed = hash_calc_task.await?;
+
+    // then look the closest images of every query up in project index.
+    let diff_results = match project_hashes.read().await.get(project_name).and_then(ProjectState::ready) {
+        Some(project) => hashed.into_iter().zip(limits.iter())
+            .map(|(hashes, limits)| hashes.map(|h| project.index.search(&h, &weights, limits)))
+            .collect(),
+        None => return Err(not_found().into()),
+    };
+
+    let calc_done = calc_start.elapsed(); // Measure load time
+
+    println!("[*] calculation task done: {:.3?}", calc_done);
+    // println!("[*] leave calculation blk");
+
+    Ok(diff_results)
+}
+
+/// Look the closest images of hashes computed by the client up in a project.
+/// 
+/// Without weights, every hash of the query is weighted the same.
+async fn calc_sim_in_project_by_hash(
+    query: &QueryHash,
+    project_name: &str,
+    weights: Option<&HashMap<HashType, f32>>,
+    limits: SearchLimits,
+    project_hashes: ProjectHashDict)
+    -> Result<Vec<ImageDistEntry>, Box<dyn Error + Send + Sync>> {
+
     let project_dict_rlock = project_hashes.read().await;
+    let Some(project) = project_dict_rlock.get(project_name).and_then(ProjectState::ready) else {
+        return Err(format!("project <{}> not found in current database", project_name).into());
+    };
 
-    // first, we should check if the project exists.
-    match (*project_dict_rlock).get(project_name) {
+    let hashes = decode_query_hash(query, &project.manifest.hasher_config, &project.manifest.hash_types())
+        .map_err(|e| e.to_string())?;
 
-        // If exists, then calculate the distance.
-        Some(hash_list) => {
-            let hash_list = hash_list.clone();
+    // only the hash types of the query can be weighted.
+    let hash_types: Vec<HashType> = hashes.keys().copied().collect();
+    let weights = weights_of(&hash_types, weights)?;
 
-            // This involves image resizing, which is a cpu task.
-            // So we put it in seprated thread. 
-            let diff_calc_task = 
-                tokio::task::spawn_blocking(move || {            
-                    let res = calc_similarity_list(&image, &hash_list);  
-                    res
-                });
+    Ok(project.index.search(&hashes, &weights, &limits))
+}
 
-            let mut diff_result = diff_calc_task.await?;
-            diff_result.sort();
+/// Build search limits from the parameters of a compare request.
+/// 
+/// Without `top_k`, the default top-3 is returned, unless a distance
+/// threshold is given, which asks for every image under it. Either way
+/// the number of images is capped to protect the service.
+fn search_limits(
+    top_k: Option<usize>,
+    max_distance: Option<f32>,
+    max_normalized_distance: Option<f32>,
+    with_image: bool)
+    -> Result<(SearchLimits, bool), AppError> {
+
+    if let Some(d) = max_distance
+        && (!d.is_finite() || d < 0.0) {
+        return Err(AppError::BadRequest("max_distance must be a non-negative number".into()));
+    }
+    if let Some(d) = max_normalized_distance
+        && !(0.0..=1.0).contains(&d) {
+        return Err(AppError::BadRequest("max_normalized_distance must be between 0 and 1".into()));
+    }
+    if top_k == Some(0) {
+        return Err(AppError::BadRequest("top_k must be positive".into()));
+    }
 
-            let calc_done = calc_start.elapsed(); // Measure load time
+    let cap = match with_image {
+        true => MAX_TOP_K_WITH_IMAGE,
+        false => MAX_TOP_K,
+    };
+
+    let has_threshold = max_distance.is_some() || max_normalized_distance.is_some();
+    let top_k = match (top_k, has_threshold) {
+        (Some(k), _) => k,
+        (None, true) => cap,
+        (None, false) => DEFAULT_TOP_K,
+    };
+
+    let limits = SearchLimits {
+        top_k: min(top_k, cap),
+        max_distance: max_distance.map(|d| d as f64),
+        max_normalized_distance: max_normalized_distance.map(|d| d as f64),
+    };
+
+    Ok((limits, top_k > cap))
+}
 
-            println!("[*] calculation task done: {:.3?}", calc_done);
-            // println!("[*] leave calculation blk");
-            
-            Ok(diff_result)
+/// Projects a compare request searches in.
+#[derive(Debug, PartialEq)]
+struct CompareProjects {
+    names: Vec<String>,
+    /// Projects the wildcard skipped, as they are not ready.
+    not_ready: usize,
+    /// Projects the wildcard skipped, as the query does not suit them.
+    unsuited: usize,
+}
 
-        },
-        None => Err(format!("project <{}> not found in current database", project_name).into()),
+/// Whether a project can answer a compare request: it indexes every
+/// weighted hash type, or for a hash query, every hash type of the query
+/// under the same hasher config.
+fn query_suits_project(payload: &CompareImageReq, project: &Project) -> bool {
+    let hash_types = project.manifest.hash_types();
+
+    match &payload.query_hash {
+        None => weights_of(&hash_types, payload.weights.as_ref()).is_ok(),
+        Some(query_hash) => query_hash.hashes.keys().all(|t| hash_types.contains(t))
+            && query_hash.hasher_config.clone().unwrap_or_default() == project.manifest.hasher_config,
+    }
+}
+
+/// Projects a compare request searches in.
+/// 
+/// Named projects must be ready to serve, while the wildcard takes every
+/// ready project the query suits and skips the others.
+fn compare_projects_of(payload: &CompareImageReq, project_dict: &HashMap<String, ProjectState>)
+    -> Result<CompareProjects, AppError> {
+
+    let project_names = match (&payload.project_names, payload.project_name.is_empty()) {
+        (Some(_), false) => return Err(AppError::BadRequest("give either project_name or project_names".into())),
+        (None, true) => return Err(AppError::BadRequest("project_name is required".into())),
+        (Some(names), true) => names.iter().unique().cloned().collect_vec(),
+        (None, false) => vec![payload.project_name.clone()],
+    };
+
+    if project_names.is_empty() {
+        return Err(AppError::BadRequest("project_names must not be empty".into()));
     }
+
+    if project_names.iter().any(|name| name == ALL_PROJECTS) {
+        let ready: Vec<(&String, &Project)> = project_dict.iter()
+            .filter_map(|(name, project)| project.ready().map(|p| (name, p)))
+            .collect();
+        let names = ready.iter()
+            .filter(|(_, project)| query_suits_project(payload, project))
+            .map(|(name, _)| (*name).clone())
+            .sorted()
+            .collect_vec();
+
+        return Ok(CompareProjects {
+            not_ready: project_dict.len() - ready.len(),
+            unsuited: ready.len() - names.len(),
+            names,
+        });
+    }
+
+    for project_name in project_names.iter() {
+        check_project_ready(project_dict, project_name)?;
+    }
+
+    Ok(CompareProjects { names: project_names, not_ready: 0, unsuited: 0 })
+}
+
+/// Decode the image of a request in a blocking thread, as large images
+/// take a while to decode.
+async fn decode_request_image<T>(image_req: &Arc<ImageRequest<T>>) -> Result<DynamicImage, AppError>
+where
+    T: FormRequest + Sync + 'static,
+{
+    let image_req = Arc::clone(image_req);
+    tokio::task::spawn_blocking(move || image_req.get_image().map_err(|e| e.to_string()))
+        .await
+        .map_err(|e| AppError::InternalError(e.to_string()))?
+        .map_err(|e| AppError::BadRequest(format!("cannot decode image: {}", e)))
 }
 
 // here's are the service handlers
 
 async fn compare_handler(
     State(state): State<AppState>, 
-    Json(payload): Json<CompareImageReq>)
+    image_req: ImageRequest<CompareImageReq>)
     -> Result<Json<CompareImageResp>, AppError> {
+
+    let image_req = Arc::new(image_req);
+    let payload = &image_req.request;
+
+    let (limits, is_capped) = search_limits(
+        payload.top_k, payload.max_distance, payload.max_normalized_distance, payload.with_image)?;
+
+    let projects = compare_projects_of(payload, &*state.project_dict.read().await)?;
+    let project_names = projects.names;
+
+    // 1. search every project, with the image from data b64 string, or
+    //    with the hashes the client sent.
+    let project_dist_vecs = match &payload.query_hash {
+        None => {
+            let image_target = decode_request_image(&image_req).await?;
+
+            calc_sim_in_projects(
+                image_target, 
+                &project_names, 
+                payload.weights.as_ref(),
+                limits,
+                Arc::clone(&state.project_dict)
+            ).await.map_err(|e| AppError::BadRequest(e.to_string()))?
+        },
+        Some(query_hash) => {
+            let mut project_dist_vecs = vec![];
+            for project_name in project_names.iter() {
+                project_dist_vecs.push(calc_sim_in_project_by_hash(
+                    query_hash,
+                    project_name,
+                    payload.weights.as_ref(),
+                    limits,
+                    Arc::clone(&state.project_dict)
+                ).await.map_err(|e| AppError::BadRequest(e.to_string()))?);
+            }
+            project_dist_vecs
+        },
+    };
+
+    // 2. merge results of every project.
+    let mut dist_vec: Vec<(&str, ImageDistEntry)> = project_names.iter().zip(project_dist_vecs)
+        .flat_map(|(project_name, project_dist_vec)| 
+            project_dist_vec.into_iter().map(|d| (project_name.as_str(), d)))
+        .collect();
+
+    // distances of projects with different hash sizes are only comparable
+    // once normalized.
+    if project_names.len() > 1 {
+        dist_vec.sort_by(|(_, a), (_, b)| a.norm_distance.total_cmp(&b.norm_distance)
+            .then(a.distance.total_cmp(&b.distance)));
+        dist_vec.truncate(limits.top_k);
+    }
+
+    let sim_vec: Vec<SimilarImageEntry> = dist_vec
+        .iter().map(
+            |(project_name, x)| dist_entry_to_api_sim_entry(
+                x, 
+                project_name,
+                payload.with_image))
+        .collect();
+
+    let mut message = match is_capped {
+        true => format!("success, results capped to {}", limits.top_k),
+        false => "success".to_owned(),
+    };
+    if projects.not_ready > 0 {
+        message = format!("{}, {} projects not ready skipped", message, projects.not_ready);
+    }
+    if projects.unsuited > 0 {
+        message = format!("{}, {} projects without the queried hash types or hasher config skipped",
+            message, projects.unsuited);
+    }
     
-    // 1. we first get the image from data b64 string
-    let image_target 
-        = payload.get_image()
-            .map_err(|e| AppError::InternalError(e.to_string()))?;
+    Ok(Json(CompareImageResp {
+        success: true,
+        message,
+        project_name: payload.project_name.clone(),
+        projects: project_names,
+        compare_result: sim_vec,
+    }))
+}
+
+async fn compare_batch_handler(
+    State(state): State<AppState>,
+    batch: ImageBatchRequest<CompareBatchReq>)
+    -> Result<Json<CompareBatchResp>, AppError> {
+
+    let payload = &batch.request;
+    println!("[*] received batch compare of {} images on <{}>", batch.len(), payload.project_name);
+
+    if batch.is_empty() || batch.len() > MAX_BATCH_IMAGES {
+        return Err(AppError::BadRequest(format!("a batch holds 1 to {} images", MAX_BATCH_IMAGES)));
+    }
+
+    // 1. limits of every query, a query may ask for its own top-K.
+    let mut limits: Vec<SearchLimits> = vec![];
+    let mut capped: Vec<bool> = vec![];
+    for query in payload.images.iter() {
+        let (query_limits, is_capped) = search_limits(
+            query.top_k.or(payload.top_k), payload.max_distance, payload.max_normalized_distance, payload.with_image)?;
+        limits.push(query_limits);
+        capped.push(is_capped);
+    }
+
+    check_project_ready(&*state.project_dict.read().await, &payload.project_name)?;
+
+    let project_name = payload.project_name.clone();
+    let image_names: Vec<String> = payload.images.iter().map(|q| q.image_name.clone()).collect();
+    let weights = payload.weights.clone();
+    let with_image = payload.with_image;
 
-    // 2. 
-    let result = calc_sim_in_project(
-        image_target, 
-        &payload.project_name, 
+    // 2. hash queries in parallel, and search them all at once.
+    let results = calc_sim_in_project_batch(
+        move |i| batch.get_image(i),
+        limits.clone(),
+        &project_name,
+        weights.as_ref(),
         state.project_dict
-    ).await.map_err(|e| AppError::BadRequest(e.to_string()));
-
-    match result {
-        Ok(dist_vec) => {
-
-            // [NOTE] we pick the top-3 entries from closest images, change if needed.
-            let ending_index = min(dist_vec.len(), 3);
-            let sim_vec: Vec<SimilarImageEntry> = (&dist_vec[0..ending_index])
-                .iter().map(
-                    |x| dist_entry_to_api_sim_entry(
-                        x, 
-                        payload.with_image))
-                .collect();
-            
-            Ok(Json(CompareImageResp {
-            success: true,
-            message: "success".to_owned(),
-            project_name: payload.project_name,
-            compare_result: sim_vec,
-        }))},
-        Err(e) => Err(e),
+    ).await.map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+    let results: Vec<CompareBatchItem> = image_names.into_iter().zip(results).zip(limits.iter().zip(capped))
+        .map(|((image_name, result), (limits, is_capped))| match result {
+            Ok(dist_vec) => CompareBatchItem {
+                image_name,
+                success: true,
+                message: match is_capped {
+                    true => format!("success, results capped to {}", limits.top_k),
+                    false => "success".to_owned(),
+                },
+                compare_result: dist_vec.iter().map(|x| dist_entry_to_api_sim_entry(x, &project_name, with_image)).collect(),
+            },
+            Err(err) => CompareBatchItem {
+                image_name,
+                success: false,
+                message: err,
+                compare_result: vec![],
+            },
+        })
+        .collect();
+
+    let compared_count = results.iter().filter(|r| r.success).count();
+
+    Ok(Json(CompareBatchResp {
+        success: compared_count == results.len(),
+        message: format!("{} of {} images compared", compared_count, results.len()),
+        project_name,
+        results,
+    }))
+}
+
+/// Measure two images with every hash type, without any project.
+async fn compare_pair_handler(
+    pair: ImageBatchRequest<ComparePairReq>)
+    -> Result<Json<ComparePairResp>, AppError> {
+
+    println!("[*] received pair compare request");
+
+    if pair.len() != 2 {
+        return Err(AppError::BadRequest("a pair compare takes exactly 2 images".into()));
     }
+
+    // every hash type the config suits is measured, the others are
+    // reported with the reason.
+    let hasher_config = pair.request.hasher_config.clone().unwrap_or_default();
+    let (hash_types, skipped): (Vec<HashType>, Vec<SkippedHashType>) = HashType::ALL.into_iter()
+        .partition_map(|t| match hasher_config.validate(&[t]) {
+            Ok(()) => Either::Left(t),
+            Err(err) => Either::Right(SkippedHashType { hash_type: t, reason: err }),
+        });
+    if hash_types.is_empty() {
+        let err = hasher_config.validate(&HashType::ALL).unwrap_err();
+        return Err(AppError::BadRequest(format!("invalid hasher_config: {}", err)));
+    }
+
+    let image_names: Vec<String> = pair.request.images.iter().map(|i| i.image_name.clone()).collect();
+
+    // decoding and hashing are cpu tasks.
+    let _hasher_config = hasher_config.clone();
+    let distances = tokio::task::spawn_blocking(move || {
+        let decode = |i: usize| pair.get_image(i)
+            .map_err(|e| format!("cannot decode image {}: {}", i + 1, e));
+        let (lhs, rhs) = (decode(0)?, decode(1)?);
+        Ok::<_, String>(calc_pair_distances(&lhs, &rhs, &hash_types, &_hasher_config))
+    }).await
+        .map_err(|e| AppError::InternalError(e.to_string()))?
+        .map_err(AppError::BadRequest)?;
+
+    let distances: Vec<PairDistance> = distances.iter()
+        .map(|d| PairDistance {
+            hash_type: d.hash_type,
+            distance: d.distance as f32,
+            normalized_distance: d.norm_distance as f32,
+            similarity: (1.0 - d.norm_distance) as f32,
+        })
+        .collect();
+
+    Ok(Json(ComparePairResp {
+        success: true,
+        message: "success".to_owned(),
+        image_names,
+        hasher_config,
+        distances,
+        skipped,
+    }))
 }
 
 async fn upload_handler(
     State(state): State<AppState>, 
-    Json(payload): Json<UploadImageReq>)
+    image_req: ImageRequest<UploadImageReq>)
     -> Result<Json<UploadImageResp>, AppError> {
     
     // 1. we first collect parameters we need
 
+    let image_req = Arc::new(image_req);
+    let payload = &image_req.request;
     let project_root = state.project_root;
-    let project_name = payload.project_name;
-    let image_name = payload.image_name;
+    let project_name = payload.project_name.clone();
+    let image_name = payload.image_name.clone();
     
-    // Validate project name to prevent path traversal attacks
+    // Validate project and image names to prevent path traversal attacks
     validate_project_name(&project_name)?;
+    validate_image_name(&image_name).map_err(AppError::BadRequest)?;
+
+    if let Some(d) = payload.duplicate_threshold
+        && !(0.0..=1.0).contains(&d) {
+        return Err(AppError::BadRequest("duplicate_threshold must be between 0 and 1".into()));
+    }
 
     // [NOTE] conside resize to save spaces.
-    let image = base64_to_image(&payload.data)
-                .map_err(|e| format!("cannot create image from b64: {}", e.to_string()))
-                .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    let image = decode_request_image(&image_req).await?;
     let project_dict = Arc::clone(&state.project_dict);
+    let token_dict = Arc::clone(&state.token_dict);
     
 
     println!("[*] received upload request on <{}>", project_name); // [NOTE] verbose
 
-    // do saving image, return 500 if failed
-    save_image_to_project(
+    check_project_ready(&*project_dict.read().await, &project_name)?;
+
+    // An existing project keeps its hash type, refuse to mix them.
+    if let (Some(requested), Some(project)) = 
+            (payload.hash_type, project_dict.read().await.get(&project_name).and_then(ProjectState::ready))
+        && requested != project.manifest.hash_type {
+        return Err(AppError::BadRequest(format!(
+            "project <{}> uses {:?}, cannot store {:?} hashes",
+            project_name, project.manifest.hash_type, requested)));
+    }
+
+    // do saving image, return 409 if the name is taken or a duplicate is
+    // refused, 500 if failed
+    let resp = save_image_to_project(
         &project_root,
-        &project_name,
-        &image,
-        &image_name,
-        HashType::PHASH, // [NOTE] [WARN] change here later
-        project_dict
+        payload,
+        image,
+        project_dict,
+        token_dict
+    ).await?;
+
+    Ok(Json(resp))
+
+}
+
+/// Upload many images to a project at once.
+///
+/// Images are decoded, hashed and staged in parallel without holding the
+/// project lock, then moved into place and indexed together. Each image succeeds or fails on
+/// its own, and every one of them is reported.
+async fn upload_batch_handler(
+    State(state): State<AppState>,
+    PathParam(project_name): PathParam<String>,
+    batch: ImageBatchRequest<UploadBatchReq>)
+    -> Result<Json<UploadBatchResp>, AppError> {
+
+    println!("[*] received batch upload of {} images on <{}>", batch.len(), project_name);
+    validate_project_name(&project_name)?;
+
+    if batch.is_empty() || batch.len() > MAX_BATCH_IMAGES {
+        return Err(AppError::BadRequest(format!("a batch holds 1 to {} images", MAX_BATCH_IMAGES)));
+    }
+
+    let project_path = Path::new(&state.project_root).join(&project_name);
+    let hash_type = batch.request.hash_type;
+    let on_collision = batch.request.on_collision;
+
+    let image_names: Vec<String> = batch.request.images.iter().map(|i| i.image_name.clone()).collect();
+    let mut outcomes: Vec<Result<(), String>> = vec![Ok(()); image_names.len()];
+
+    // 1. make sure the project exists, take its manifest, and name every
+    // image as the collision policy asks. Names are reserved until the
+    // images are stored.
+    let mut to_store: Vec<usize> = vec![];
+    let mut store_names: Vec<String> = vec![];
+    let mut overwrites: Vec<bool> = vec![];
+    let manifest = {
+        let mut project_dict_wlock = state.project_dict.write().await;
+        check_project_ready(&project_dict_wlock, &project_name)?;
+
+        if let (Some(requested), Some(project)) = 
+                (hash_type, project_dict_wlock.get(&project_name).and_then(ProjectState::ready))
+            && requested != project.manifest.hash_type {
+            return Err(AppError::BadRequest(format!(
+                "project <{}> uses {:?}, cannot store {:?} hashes",
+                project_name, project.manifest.hash_type, requested)));
+        }
+
+        let manifest = ensure_project(&project_path, &project_name, hash_type, &mut project_dict_wlock)
+            .map_err(|e| AppError::InternalError(e.to_string()))?;
+        let project = project_dict_wlock.get_mut(&project_name).and_then(ProjectState::ready_mut)
+            .ok_or_else(|| AppError::InternalError(format!("project <{}> not found in current database", project_name)))?;
+
+        // a name given twice is only stored once, unless renamed.
+        for (i, image_name) in image_names.iter().enumerate() {
+            let stored_name = validate_image_name(image_name)
+                .and_then(|_| match on_collision != CollisionPolicy::Rename && image_names[..i].contains(image_name) {
+                    true => Err("image name given more than once in the batch".to_owned()),
+                    false => resolve_image_name(&project_path, image_name, on_collision, &project.reserved_names),
+                });
+
+            match stored_name {
+                Ok((stored_name, is_overwrite)) => {
+                    project.reserved_names.insert(stored_name.clone());
+                    to_store.push(i);
+                    store_names.push(stored_name);
+                    overwrites.push(is_overwrite);
+                },
+                Err(err) => outcomes[i] = Err(err),
+            }
+        }
+
+        manifest
+    };
+
+    // 2. hash and stage images in parallel.
+    let _project_path = project_path.clone();
+    let _store_names = store_names.clone();
+    let _to_store = to_store.clone();
+    let staged = tokio::task::spawn_blocking(move || {
+        stage_project_images(&_project_path, &manifest, &_store_names, hash_workers(), |k| batch.get_image(_to_store[k]))
+    }).await.map_err(|e| AppError::InternalError(e.to_string()))?;
+
+    // 3. move every staged image into place and index it under one lock,
+    // and give names back. Staged images which are not stored are removed.
+    let mut to_put: Vec<StagedImage> = vec![];
+    let mut put_indices: Vec<usize> = vec![];
+    for (k, result) in staged.into_iter().enumerate() {
+        match result {
+            Ok(staged) => {
+                to_put.push(staged);
+                put_indices.push(to_store[k]);
+            },
+            Err(err) => outcomes[to_store[k]] = Err(err),
+        }
+    }
+
+    let mut project_dict_wlock = state.project_dict.write().await;
+    release_image_names(&mut project_dict_wlock, &project_name, &store_names);
+    let put_results = match project_dict_wlock.get_mut(&project_name).and_then(ProjectState::ready_mut) {
+        Some(project) => project.put_staged_images(to_put).map_err(|e| e.to_string()),
+        None => {
+            to_put.into_iter().for_each(StagedImage::discard);
+            Err(format!("project <{}> not found in current database", project_name))
+        },
+    };
+    match put_results {
+        Ok(put_results) => put_indices.iter().zip(put_results).for_each(|(&i, result)| outcomes[i] = result),
+        Err(err) => put_indices.iter().for_each(|&i| outcomes[i] = Err(err.clone())),
+    }
+
+    let overwritten: Vec<String> = store_names.iter().zip(overwrites)
+        .filter(|(_, is_overwrite)| *is_overwrite)
+        .map(|(name, _)| name.clone())
+        .collect();
+    let mut stored_names: HashMap<usize, String> = to_store.into_iter().zip(store_names).collect();
+    stored_names.retain(|&i, _| outcomes[i].is_ok());
+    let indexed: Vec<usize> = stored_names.keys().copied().sorted().collect();
+
+    // 4. mint deletion tokens of indexed images, tokens of overwritten
+    // images do not delete the new ones. Tokens are locked before the
+    // project is released, so uploads of the same names mint in the order
+    // they stored.
+    let mut tokens: HashMap<usize, String> = HashMap::new();
+    let mut token_dict_wlock = state.token_dict.write().await;
+    drop(project_dict_wlock);
+    if !indexed.is_empty() {
+        let indexed_names: Vec<String> = indexed.iter().map(|&i| stored_names[&i].clone()).collect();
+
+        let overwritten: Vec<String> = overwritten.into_iter().filter(|name| indexed_names.contains(name)).collect();
+        forget_image_tokens(&mut token_dict_wlock, &project_path, &project_name, &overwritten)
+            .map_err(|e| AppError::InternalError(format!("cannot persist deletion tokens: {}", e)))?;
+        let minted = mint_deletion_tokens(&mut token_dict_wlock, &project_path, &project_name, &indexed_names)
+            .map_err(|e| AppError::InternalError(format!("cannot persist deletion tokens: {}", e)))?;
+        tokens.extend(indexed.iter().copied().zip(minted));
+    }
+    drop(token_dict_wlock);
+
+    let results: Vec<UploadBatchItem> = image_names.into_iter().zip(outcomes).enumerate()
+        .map(|(i, (image_name, outcome))| UploadBatchItem {
+            image_name,
+            success: outcome.is_ok(),
+            token: tokens.remove(&i),
+            stored_name: stored_names.remove(&i).filter(|_| outcome.is_ok()),
+            message: match outcome {
+                Ok(()) => "image uploaded and indexed successfully".to_owned(),
+                Err(err) => err,
+            },
+        })
+        .collect();
+
+    let stored_count = results.iter().filter(|r| r.success).count();
+    println!("[*] stored {}/{} images of batch on <{}>", stored_count, results.len(), project_name);
+
+    Ok(Json(UploadBatchResp {
+        success: stored_count == results.len(),
+        message: format!("{} of {} images uploaded", stored_count, results.len()),
+        results,
+    }))
+}
+
+async fn remove_image_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<RemoveImageReq>)
+    -> Result<Json<RemoveImageResp>, AppError> {
+
+    println!("[*] received remove image request"); // [NOTE] verbose
+
+    // the image cannot be dropped from a project which is still loading.
+    let project_name = find_image_token(&*state.token_dict.read().await, &payload.token)
+        .map(|t| t.project_name);
+    if let Some(project_name) = project_name {
+        check_project_ready(&*state.project_dict.read().await, &project_name)?;
+    }
+
+    let removed = remove_image_from_project(
+        &state.project_root,
+        &payload.token,
+        Arc::clone(&state.project_dict),
+        Arc::clone(&state.token_dict),
     ).await.map_err(|e| AppError::InternalError(e.to_string()))?;
 
-    Ok(Json(UploadImageResp {
+    match removed {
+        Some(t) => Ok(Json(RemoveImageResp {
+            success: true,
+            message: format!("Image '{}' removed from project '{}'", t.image_name, t.project_name),
+        })),
+        None => Ok(Json(RemoveImageResp {
+            success: false,
+            message: "token expired or invalid".to_owned(),
+        })),
+    }
+}
+
+async fn create_project_handler(
+    State(state): State<AppState>,
+    PathParam(project_name): PathParam<String>,
+    payload: Option<Json<CreateProjectReq>>)
+    -> Result<Json<CreateProjectResp>, AppError> {
+
+    println!("[*] received create project request for: <{}>", project_name);
+    validate_project_name(&project_name)?;
+    let project_path = Path::new(&state.project_root).join(&project_name);
+    let Json(payload) = payload.unwrap_or_default();
+
+    let mut project_dict_wlock = state.project_dict.write().await;
+
+    // Check if project exists
+    if let Some(project_state) = project_dict_wlock.get(&project_name) {
+        let manifest = project_state.ready().map(|p| p.manifest.clone());
+        return Ok(Json(CreateProjectResp {
+            success: false,
+            message: format!("Project '{}' already exists", project_name),
+            hash_type: manifest.as_ref().map(|m| m.hash_type),
+            extra_hash_types: manifest.as_ref().map(|m| m.extra_hash_types.clone()).unwrap_or_default(),
+            hasher_config: manifest.map(|m| m.hasher_config),
+        }));
+    }
+    if project_path.exists() {
+        return Ok(Json(CreateProjectResp {
+            success: false,
+            message: format!("Project '{}' already exists", project_name),
+            hash_type: None,
+            extra_hash_types: vec![],
+            hasher_config: None,
+        }));
+    }
+
+    let manifest = ProjectManifest {
+        hash_type: payload.hash_type.unwrap_or(ProjectManifest::default().hash_type),
+        extra_hash_types: payload.extra_hash_types,
+        hasher_config: payload.hasher_config.unwrap_or_default(),
+    };
+    manifest.hasher_config.validate(&manifest.hash_types())
+        .map_err(|e| AppError::BadRequest(format!("invalid hasher_config: {}", e)))?;
+
+    let manifest = create_project(
+        &project_path,
+        &project_name,
+        manifest,
+        &mut project_dict_wlock,
+    ).map_err(|e| AppError::InternalError(e.to_string()))?;
+
+    Ok(Json(CreateProjectResp {
         success: true,
-        message: "image uploaded and indexed successfully".to_owned(),
-        token: "dummy-deletion-token".to_string(), // [WARN] [NOTE] change later to proper uuid
+        message: format!("Project '{}' created successfully", project_name),
+        hash_type: Some(manifest.hash_type),
+        extra_hash_types: manifest.extra_hash_types,
+        hasher_config: Some(manifest.hasher_config),
     }))
-
 }
 
 async fn delete_project_handler(
@@ -290,11 +1418,13 @@ async fn delete_project_handler(
         }));
     }
 
-    // Remove from in-memory hash dict first
-    {
-        let mut project_dict_wlock = project_dict.write().await;
-        project_dict_wlock.remove(&project_name);
-    }
+    // Remove from in-memory hash dict first, together with its tokens.
+    // The lock is held until the folder is gone, so the project watcher
+    // never sees a half-deleted folder of an unknown project.
+    let mut project_dict_wlock = project_dict.write().await;
+    project_dict_wlock.remove(&project_name);
+
+    state.token_dict.write().await.remove(&project_name);
 
     // Delete the project directory
     match remove_dir_all(&project_path) {
@@ -312,9 +1442,408 @@ async fn delete_project_handler(
 }
 
 
+/// Describe a project from its state. Folder size and time are left empty,
+/// see `describe_project_folder`.
+fn describe_project(project_name: &str, project_state: &ProjectState) -> ProjectInfo {
+    let mut info = ProjectInfo {
+        project_name: project_name.to_owned(),
+        state: ProjectLoadState::Ready,
+        progress: None,
+        error: None,
+        image_count: None,
+        hash_type: None,
+        extra_hash_types: vec![],
+        hasher_config: None,
+        disk_size: 0,
+        last_modified: None,
+    };
+
+    match project_state {
+        ProjectState::Ready(project) => {
+            info.image_count = Some(project.index.len());
+            info.hash_type = Some(project.manifest.hash_type);
+            info.extra_hash_types = project.manifest.extra_hash_types.clone();
+            info.hasher_config = Some(project.manifest.hasher_config.clone());
+        },
+        ProjectState::Loading(progress) => {
+            info.state = ProjectLoadState::Loading;
+            info.progress = Some(LoadProgress { done: progress.done(), total: progress.total() });
+        },
+        ProjectState::Failed(err) => {
+            info.state = ProjectLoadState::Failed;
+            info.error = Some(err.clone());
+        },
+    }
+
+    info
+}
+
+/// Fill in what is only known from the project folder: its size, last
+/// change, and the manifest of projects which are not loaded.
+fn describe_project_folder(info: &mut ProjectInfo, project_path: &Path) {
+    match project_disk_usage(project_path) {
+        Ok(usage) => {
+            info.disk_size = usage.size;
+            info.last_modified = usage.last_modified
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs());
+        },
+        Err(err) => println!("[!] cannot measure project <{}>: {}", info.project_name, err),
+    }
+
+    if info.hash_type.is_none()
+        && let Ok(Some(manifest)) = load_project_manifest(project_path) {
+        info.hash_type = Some(manifest.hash_type);
+        info.extra_hash_types = manifest.extra_hash_types;
+        info.hasher_config = Some(manifest.hasher_config);
+    }
+}
+
+/// List every known project, by name.
+async fn list_projects_handler(State(state): State<AppState>) -> Result<Json<ListProjectsResp>, AppError> {
+    let mut projects: Vec<ProjectInfo> = state.project_dict.read().await.iter()
+        .map(|(project_name, project_state)| describe_project(project_name, project_state))
+        .collect();
+    projects.sort_by(|a, b| a.project_name.cmp(&b.project_name));
+
+    // folders are walked without holding the lock.
+    let project_root = PathBuf::from(&state.project_root);
+    let projects = tokio::task::spawn_blocking(move || {
+        for info in projects.iter_mut() {
+            describe_project_folder(info, &project_root.join(&info.project_name));
+        }
+        projects
+    }).await.map_err(|e| AppError::InternalError(e.to_string()))?;
+
+    Ok(Json(ListProjectsResp {
+        success: true,
+        message: format!("{} projects", projects.len()),
+        projects,
+    }))
+}
+
+/// Describe one project.
+async fn get_project_handler(
+    State(state): State<AppState>,
+    PathParam(project_name): PathParam<String>)
+    -> Result<Json<GetProjectResp>, AppError> {
+
+    validate_project_name(&project_name)?;
+
+    let mut info = match state.project_dict.read().await.get(&project_name) {
+        Some(project_state) => describe_project(&project_name, project_state),
+        None => return Err(AppError::NotFound(format!("project <{}> not found in current database", project_name))),
+    };
+
+    let project_path = Path::new(&state.project_root).join(&project_name);
+    let project = tokio::task::spawn_blocking(move || {
+        describe_project_folder(&mut info, &project_path);
+        info
+    }).await.map_err(|e| AppError::InternalError(e.to_string()))?;
+
+    Ok(Json(GetProjectResp {
+        success: true,
+        message: "success".to_owned(),
+        project,
+    }))
+}
+
+/// List indexed images of a project, a page at a time.
+async fn list_images_handler(
+    State(state): State<AppState>,
+    PathParam(project_name): PathParam<String>,
+    Query(query): Query<ListImagesQuery>)
+    -> Result<Json<ListImagesResp>, AppError> {
+
+    validate_project_name(&project_name)?;
+
+    let page = query.page.unwrap_or(1);
+    let page_size = query.page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+    if page == 0 {
+        return Err(AppError::BadRequest("page starts from 1".into()));
+    }
+    if page_size == 0 || page_size > MAX_PAGE_SIZE {
+        return Err(AppError::BadRequest(format!("page_size must be between 1 and {}", MAX_PAGE_SIZE)));
+    }
+
+    let image_stamps = {
+        let project_dict_rlock = state.project_dict.read().await;
+        check_project_ready(&project_dict_rlock, &project_name)?;
+        match project_dict_rlock.get(&project_name).and_then(ProjectState::ready) {
+            Some(project) => project.image_stamps(),
+            None => return Err(AppError::NotFound(format!("project <{}> not found in current database", project_name))),
+        }
+    };
+
+    let filter = query.filter.map(|f| f.to_lowercase());
+    let mut images: Vec<ImageInfo> = image_stamps.into_iter()
+        .filter(|(image_name, _)| filter.as_ref().is_none_or(|f| image_name.to_lowercase().contains(f)))
+        .map(|(image_name, stamp)| ImageInfo {
+            image_name,
+            size: stamp.map_or(0, |s| s.size),
+            last_modified: stamp.and_then(|s| s.modified_ns).map(|ns| ns / 1_000_000_000),
+        })
+        .collect();
+
+    // ties are broken by name, so pages are stable.
+    match query.sort {
+        ImageSortKey::Name => images.sort_by(|a, b| a.image_name.cmp(&b.image_name)),
+        ImageSortKey::Size => images.sort_by(|a, b| a.size.cmp(&b.size).then_with(|| a.image_name.cmp(&b.image_name))),
+        ImageSortKey::Modified => images.sort_by(|a, b| 
+            a.last_modified.cmp(&b.last_modified).then_with(|| a.image_name.cmp(&b.image_name))),
+    }
+    if query.order == SortOrder::Desc {
+        images.reverse();
+    }
+
+    let total = images.len();
+    let images: Vec<ImageInfo> = images.into_iter()
+        .skip((page - 1).saturating_mul(page_size))
+        .take(page_size)
+        .collect();
+
+    Ok(Json(ListImagesResp {
+        success: true,
+        message: format!("{} of {} images", images.len(), total),
+        total,
+        page,
+        page_size,
+        images,
+    }))
+}
+
+/// Path of an indexed image of a project. Only indexed images are served,
+/// so index, manifest and token files never are.
+async fn indexed_image_path(state: &AppState, project_name: &str, image_name: &str) -> Result<PathBuf, AppError> {
+    validate_project_name(project_name)?;
+
+    let mut comps = Path::new(image_name).components();
+    if !matches!((comps.next(), comps.next()), (Some(Component::Normal(_)), None)) {
+        return Err(AppError::BadRequest("invalid image name".into()));
+    }
+
+    let image_path = Path::new(&state.project_root).join(project_name).join(image_name);
+
+    let project_dict_rlock = state.project_dict.read().await;
+    check_project_ready(&project_dict_rlock, project_name)?;
+    match project_dict_rlock.get(project_name).and_then(ProjectState::ready) {
+        Some(project) if project.index.get(&image_path).is_some() => Ok(image_path),
+        Some(_) => Err(AppError::NotFound(format!("image <{}> not found in project <{}>", image_name, project_name))),
+        None => Err(AppError::NotFound(format!("project <{}> not found in current database", project_name))),
+    }
+}
+
+/// Serve the bytes of an image as stored.
+async fn get_image_handler(
+    State(state): State<AppState>,
+    PathParam((project_name, image_name)): PathParam<(String, String)>)
+    -> Result<Response<Body>, AppError> {
+
+    let image_path = indexed_image_path(&state, &project_name, &image_name).await?;
+
+    let data = match tokio::fs::read(&image_path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 
+            return Err(AppError::NotFound(format!("image <{}> not found in project <{}>", image_name, project_name))),
+        Err(e) => return Err(AppError::InternalError(format!("cannot read image: {}", e))),
+    };
+
+    let content_type = image_content_type(&image_path).unwrap_or("application/octet-stream");
+
+    Ok(([(http::header::CONTENT_TYPE, content_type)], data).into_response())
+}
+
+/// Serve a PNG thumbnail of an image, keeping its aspect ratio.
+async fn get_thumbnail_handler(
+    State(state): State<AppState>,
+    PathParam((project_name, image_name)): PathParam<(String, String)>,
+    Query(query): Query<ThumbnailQuery>)
+    -> Result<Response<Body>, AppError> {
+
+    let size = query.size.unwrap_or(DEFAULT_THUMBNAIL_SIZE);
+    if size == 0 || size > MAX_THUMBNAIL_SIZE {
+        return Err(AppError::BadRequest(format!("size must be between 1 and {}", MAX_THUMBNAIL_SIZE)));
+    }
+
+    let image_path = indexed_image_path(&state, &project_name, &image_name).await?;
+
+    let thumbnail = tokio::task::spawn_blocking(move || -> Result<Vec<u8>, String> {
+        let image = image::open(&image_path).map_err(|e| format!("cannot open image: {}", e))?;
+        image_to_png(&image.thumbnail(size, size)).map_err(|e| e.to_string())
+    }).await.map_err(|e| AppError::InternalError(e.to_string()))?
+        .map_err(AppError::InternalError)?;
+
+    Ok(([(http::header::CONTENT_TYPE, "image/png")], thumbnail).into_response())
+}
+
+/// Report the clusters of near-duplicate images of a project.
+async fn duplicates_handler(
+    State(state): State<AppState>,
+    PathParam(project_name): PathParam<String>,
+    Query(query): Query<DuplicatesQuery>)
+    -> Result<Json<DuplicatesResp>, AppError> {
+
+    println!("[*] received duplicate report request for: <{}>", project_name);
+    validate_project_name(&project_name)?;
+
+    let threshold = query.threshold.unwrap_or(DEFAULT_DUPLICATE_THRESHOLD);
+    if !(0.0..=MAX_DUPLICATE_THRESHOLD).contains(&threshold) {
+        return Err(AppError::BadRequest(format!("threshold must be between 0 and {}", MAX_DUPLICATE_THRESHOLD)));
+    }
+
+    // the index is copied, so the project is not locked while clustering.
+    let (index, weights) = {
+        let project_dict_rlock = state.project_dict.read().await;
+        check_project_ready(&project_dict_rlock, &project_name)?;
+
+        let project = project_dict_rlock.get(&project_name).and_then(ProjectState::ready)
+            .ok_or_else(|| AppError::NotFound(format!("project <{}> not found in current database", project_name)))?;
+        (project.index.clone(), HashWeights::uniform(&project.manifest.hash_types()))
+    };
+    let image_count = index.len();
+
+    // every image is looked up, so it runs in a blocking thread.
+    let (clusters, truncated) = tokio::task::spawn_blocking(move || {
+        duplicate_clusters(&index, &weights, threshold as f64, MAX_DUPLICATE_PAIRS)
+    }).await.map_err(|e| AppError::InternalError(e.to_string()))?;
+
+    let clusters: Vec<DuplicateClusterInfo> = clusters.into_iter()
+        .map(|cluster| DuplicateClusterInfo {
+            distances: cluster.distances.iter()
+                .map(|d| DuplicatePair {
+                    image_a: cluster.members[d.a].clone(),
+                    image_b: cluster.members[d.b].clone(),
+                    distance: d.distance as f32,
+                    similarity: (1.0 - d.norm_distance) as f32,
+                })
+                .collect(),
+            images: cluster.members,
+        })
+        .collect();
+
+    let duplicate_count = clusters.iter().map(|c| c.images.len()).sum();
+
+    let mut message = format!("{} clusters of near-duplicates", clusters.len());
+    if truncated {
+        message = format!("{}, the search stopped at {} pairs", message, MAX_DUPLICATE_PAIRS);
+    }
+
+    Ok(Json(DuplicatesResp {
+        success: true,
+        message,
+        project_name,
+        image_count,
+        duplicate_count,
+        truncated,
+        clusters,
+    }))
+}
+
+/// Export the hashes of every image of a project.
+async fn hashes_handler(
+    State(state): State<AppState>,
+    PathParam(project_name): PathParam<String>,
+    Query(query): Query<HashesQuery>)
+    -> Result<Json<ProjectHashesResp>, AppError> {
+
+    println!("[*] received hash export request for: <{}>", project_name);
+    validate_project_name(&project_name)?;
+
+    let project_dict_rlock = state.project_dict.read().await;
+    check_project_ready(&project_dict_rlock, &project_name)?;
+    let Some(project) = project_dict_rlock.get(&project_name).and_then(ProjectState::ready) else {
+        return Err(AppError::NotFound(format!("project <{}> not found in current database", project_name)));
+    };
+
+    let hasher_config = project.manifest.hasher_config.clone();
+    let bit_lengths = project.manifest.hash_types().into_iter()
+        .map(|t| (t, hash_bit_len(t, &hasher_config)))
+        .collect();
+
+    let mut images: Vec<ImageHashInfo> = project.index.iter()
+        .map(|entry| hash_entry_to_api_hash_info(entry, query.encoding))
+        .collect();
+    drop(project_dict_rlock);
+
+    images.sort_by(|a, b| a.image_name.cmp(&b.image_name));
+
+    Ok(Json(ProjectHashesResp {
+        success: true,
+        message: format!("{} images", images.len()),
+        project_name,
+        hasher_config,
+        encoding: query.encoding,
+        bit_lengths,
+        images,
+    }))
+}
+
+/// Resync a project with its folder: hash new and changed images, and drop
+/// the ones which are gone. With `force`, every image is hashed again.
+async fn rescan_project_handler(
+    State(state): State<AppState>,
+    PathParam(project_name): PathParam<String>,
+    payload: Option<Json<RescanProjectReq>>)
+    -> Result<Json<RescanProjectResp>, AppError> {
+
+    println!("[*] received rescan request for: <{}>", project_name);
+    validate_project_name(&project_name)?;
+    let project_path = Path::new(&state.project_root).join(&project_name);
+    let Json(payload) = payload.unwrap_or_default();
+    let not_found = || AppError::NotFound(format!("project <{}> not found in current database", project_name));
+
+    check_project_ready(&*state.project_dict.read().await, &project_name)?;
+
+    let image_paths = match project_path.is_dir() {
+        true => list_project_images(&project_path).map_err(|e| AppError::InternalError(e.to_string()))?,
+        false => return Err(not_found()),
+    };
+
+    // 1. compare the index with the disk
+    let (manifest, plan) = {
+        let project_dict_rlock = state.project_dict.read().await;
+        let project = project_dict_rlock.get(&project_name).and_then(ProjectState::ready).ok_or_else(not_found)?;
+        (project.manifest.clone(), project.plan_rescan(image_paths, payload.force))
+    };
+
+    // 2. hash new and changed images, without holding the lock
+    let to_hash = plan.to_hash.clone();
+    let hashed = tokio::task::spawn_blocking(move || hash_project_images(&to_hash, &manifest, hash_workers()))
+        .await
+        .map_err(|e| AppError::InternalError(e.to_string()))?;
+
+    // 3. apply the changes, then forget tokens of removed images
+    let mut project_dict_wlock = state.project_dict.write().await;
+    let project = project_dict_wlock.get_mut(&project_name).and_then(ProjectState::ready_mut).ok_or_else(not_found)?;
+
+    let summary = project.apply_rescan(plan, hashed)
+        .map_err(|e| AppError::InternalError(format!("cannot update project index: {}", e)))?;
+
+    forget_image_tokens(&mut *state.token_dict.write().await, &project_path, &project_name, &summary.removed)
+        .map_err(|e| AppError::InternalError(format!("cannot persist deletion tokens: {}", e)))?;
+
+    println!("[*] rescanned project <{}>: {} added, {} changed, {} removed, {} unchanged, {} failed",
+        project_name, summary.added.len(), summary.changed.len(), summary.removed.len(), summary.unchanged, summary.failed.len());
+
+    Ok(Json(RescanProjectResp {
+        success: summary.failed.is_empty(),
+        message: match summary.failed.is_empty() {
+            true => format!("Project '{}' rescanned successfully", project_name),
+            false => format!("Project '{}' rescanned, {} images cannot be hashed", project_name, summary.failed.len()),
+        },
+        added: summary.added,
+        changed: summary.changed,
+        removed: summary.removed,
+        unchanged: summary.unchanged,
+        failed: summary.failed.into_iter()
+            .map(|(image_name, message)| RescanFailure { image_name, message })
+            .collect(),
+    }))
+}
+
 /// Handler for "404 not found" error, returning plain text body.
 async fn not_found_handler() -> Response<Body> { 
-    let mut response = Response::builder()
+    let response = Response::builder()
         .status(StatusCode::NOT_FOUND)
         .header(http::header::CONTENT_TYPE, "application/json")
         .header(http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
@@ -331,8 +1860,6 @@ async fn main() {
 
     // Stage 1: check prerequisites
 
-    let standard_hash_type: HashType = HashType::PHASH;
-
     let load_all = Instant::now(); // Measure load time
 
     let project_root: &Path = Path::new("./image_root");
@@ -349,9 +1876,8 @@ async fn main() {
             }
         },
         true => {
-            match project_root.is_dir() {
-                false => panic!("[x] project folder is not valid, shutting down."),
-                true => {}, // Do nothing, continue the service process
+            if !project_root.is_dir() {
+                panic!("[x] project folder is not valid, shutting down.");
             }
         }
     }
@@ -369,24 +1895,67 @@ async fn main() {
                 .partition_result();
 
 
-    // Load and create a list of tuple (project name, [hash entries])
-    let (children_project_hashes, _): 
-        (Vec<(String, Vec<ImageHashEntry>)>, Vec<_>) = 
-            children_projects.into_iter()
-                .map(|f: PathBuf| {
-                    match load_or_calc_project_hashes(&f, standard_hash_type) {
-                        Ok(h) => {
-                            let project_name = 
-                                f.file_name().ok_or("invalid project name")?;
-                            Ok((project_name.to_string_lossy().into_owned(), h))
-                        },
-                        Err(err) => Err(err),
-                    }})
-                .partition_result();
+    // Read manifests, projects without manifest are hashed with the default hash type.
+    // Every project starts loading, hashes are calculated in background later.
+    let mut project_states: HashMap<String, ProjectState> = HashMap::new();
+    let mut projects_to_load: Vec<(String, PathBuf, ProjectManifest, Arc<HashProgress>)> = vec![];
+
+    for f in children_projects {
+        let project_name = match f.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue,
+        };
+
+        match load_or_init_project_manifest(&f) {
+            Ok(manifest) => {
+                let progress = Arc::new(HashProgress::new());
+                project_states.insert(project_name.clone(), ProjectState::Loading(Arc::clone(&progress)));
+                projects_to_load.push((project_name, f, manifest, progress));
+            },
+            Err(err) => {
+                println!("[!] cannot load project <{}>: {}", project_name, err);
+                project_states.insert(project_name, ProjectState::Failed(err.to_string()));
+            },
+        }
+    }
+
+    // Collect deletion tokens of every project.
+    let mut token_map: HashMap<String, ProjectTokens> = HashMap::new();
+    for project_name in project_states.keys() {
+        match ProjectTokens::open(&project_root.join(project_name)) {
+            Ok(tokens) => { token_map.insert(project_name.clone(), tokens); },
+            Err(err) => println!("[!] cannot load tokens of project <{}>: {}", project_name, err),
+        }
+    }
 
     // Create a Arc to wrap shared project hashes.
     let project_name_hash_map: ProjectHashDict
-            = Arc::new(RwLock::new(children_project_hashes.into_iter().collect()));
+            = Arc::new(RwLock::new(project_states));
+    let token_dict: TokenDict = Arc::new(RwLock::new(token_map));
+
+    // Watch project folders before loading them, so no change is missed.
+    if watch_projects() {
+        let watch_result = watcher::spawn_project_watcher(
+            project_root, Arc::clone(&project_name_hash_map), Arc::clone(&token_dict));
+        if let Err(err) = watch_result {
+            println!("[!] cannot watch project root, new images are only indexed on restart: {}", err);
+        }
+    }
+
+    // Hash images of every project on a shared pool of workers, in background,
+    // so the service starts listening at once.
+    let loader_dict = Arc::clone(&project_name_hash_map);
+    tokio::task::spawn_blocking(move || {
+        let (projects, progress): (Vec<(PathBuf, ProjectManifest)>, Vec<Arc<HashProgress>>) = 
+            projects_to_load.iter()
+                .map(|(_, f, manifest, progress)| ((f.clone(), manifest.clone()), Arc::clone(progress)))
+                .unzip();
+
+        load_projects(&projects, hash_workers(), &progress, |i, project| {
+            let (project_name, _, _, progress) = &projects_to_load[i];
+            publish_loaded_project(&loader_dict, project_name, progress, project);
+        });
+    });
 
     let load_all_done = load_all.elapsed(); // Measure load time
 
@@ -404,9 +1973,12 @@ async fn main() {
 
 
     // Stage 3: starting service
+    let body_limit = body_limit();
     let axum_state: AppState = AppState { 
         project_root: project_root.to_string_lossy().to_string(),
-        project_dict: project_name_hash_map };
+        project_dict: project_name_hash_map,
+        token_dict,
+        body_limit };
 
     // Configure CORS to allow requests from frontend
     let cors = CorsLayer::new()
@@ -417,9 +1989,21 @@ async fn main() {
     
     let axum_app: Router = Router::new()
                     .route("/diff", post(compare_handler))
+                    .route("/diff/batch", post(compare_batch_handler))
+                    .route("/compare/pair", post(compare_pair_handler))
                     .route("/upload", post(upload_handler))
-                    .route("/project/{project_name}", delete(delete_project_handler))
+                    .route("/project/{project_name}/upload/batch", post(upload_batch_handler))
+                    .route("/image", delete(remove_image_handler))
+                    .route("/projects", get(list_projects_handler))
+                    .route("/project/{project_name}", get(get_project_handler).post(create_project_handler).delete(delete_project_handler))
+                    .route("/project/{project_name}/rescan", post(rescan_project_handler))
+                    .route("/project/{project_name}/duplicates", get(duplicates_handler))
+                    .route("/project/{project_name}/hashes", get(hashes_handler))
+                    .route("/project/{project_name}/images", get(list_images_handler))
+                    .route("/project/{project_name}/images/{image_name}", get(get_image_handler))
+                    .route("/project/{project_name}/images/{image_name}/thumbnail", get(get_thumbnail_handler))
                     .fallback(not_found_handler)
+                    .layer(DefaultBodyLimit::max(body_limit.0))
                     .with_state(axum_state)
                     .layer(cors);
 
@@ -427,3 +2011,133 @@ async fn main() {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_project(project_path: &Path, extra_hash_types: Vec<HashType>) -> ProjectState {
+        std::fs::create_dir(project_path).unwrap();
+        let (db, _) = ProjectDb::open(project_path).unwrap();
+
+        let manifest = ProjectManifest { extra_hash_types, ..ProjectManifest::default() };
+        ProjectState::Ready(Box::new(Project::new(manifest, db)))
+    }
+
+    fn mk_req(project_name: &str, project_names: Option<Vec<&str>>) -> CompareImageReq {
+        CompareImageReq {
+            project_name: project_name.to_owned(),
+            project_names: project_names.map(|names| names.into_iter().map(String::from).collect()),
+            data: String::new(),
+            query_hash: None,
+            with_image: false,
+            weights: None,
+            top_k: None,
+            max_distance: None,
+            max_normalized_distance: None,
+        }
+    }
+
+    fn mk_query_hash(hash_types: &[HashType], hasher_config: Option<HasherConfig>) -> QueryHash {
+        QueryHash {
+            hashes: hash_types.iter().map(|t| (*t, "00".to_owned())).collect(),
+            encoding: HashEncoding::default(),
+            hasher_config,
+        }
+    }
+
+    #[test]
+    fn test_validate_image_name() {
+        assert!(validate_image_name("a.png").is_ok());
+        assert!(validate_image_name("cat 1.JPG").is_ok());
+
+        for name in ["", "../a.png", "/abs.png", "a/b.png", "a\\b.png", "..", ".index.vmdb", ".a.png",
+                "a\nb.png", "a\tb.png", "a\u{7f}.png", "a", "a.txt"] {
+            assert!(validate_image_name(name).is_err(), "{:?} should be refused", name);
+        }
+
+        let long_name = format!("{}.png", "a".repeat(MAX_IMAGE_NAME_LEN));
+        assert!(validate_image_name(&long_name).is_err());
+    }
+
+    #[test]
+    fn test_resolve_image_name() {
+        let project_path = std::env::temp_dir().join(format!("vismatch-main-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&project_path).unwrap();
+        let mut reserved = HashSet::new();
+        let resolve = |policy, reserved: &HashSet<String>| resolve_image_name(&project_path, "x.png", policy, reserved);
+
+        // a free name is given as is, without touching the folder.
+        assert_eq!(resolve(CollisionPolicy::Reject, &reserved), Ok(("x.png".to_owned(), false)));
+        assert!(!project_path.join("x.png").exists());
+
+        // a reserved name is neither overwritten nor rejected for good.
+        reserved.insert("x.png".to_owned());
+        assert!(resolve(CollisionPolicy::Reject, &reserved).is_err());
+        assert!(resolve(CollisionPolicy::Overwrite, &reserved).is_err());
+
+        // every rename gets a name of its own.
+        assert_eq!(resolve(CollisionPolicy::Rename, &reserved), Ok(("x_1.png".to_owned(), false)));
+        reserved.insert("x_1.png".to_owned());
+        assert_eq!(resolve(CollisionPolicy::Rename, &reserved), Ok(("x_2.png".to_owned(), false)));
+
+        // a stored image is overwritten once its name is released.
+        std::fs::write(project_path.join("x.png"), b"").unwrap();
+        reserved.remove("x.png");
+        assert!(resolve(CollisionPolicy::Reject, &reserved).is_err());
+        assert_eq!(resolve(CollisionPolicy::Overwrite, &reserved), Ok(("x.png".to_owned(), true)));
+
+        // a released rename is free again.
+        reserved.remove("x_1.png");
+        assert_eq!(resolve(CollisionPolicy::Rename, &reserved), Ok(("x_1.png".to_owned(), false)));
+
+        std::fs::remove_dir_all(&project_path).unwrap();
+    }
+
+    #[test]
+    fn test_compare_projects_of() {
+        let project_root = std::env::temp_dir().join(format!("vismatch-main-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&project_root).unwrap();
+        let project_dict = HashMap::from([
+            ("p".to_owned(), mk_project(&project_root.join("p"), vec![])),
+            ("pd".to_owned(), mk_project(&project_root.join("pd"), vec![HashType::DHASH])),
+            ("loading".to_owned(), ProjectState::Loading(Arc::new(HashProgress::new()))),
+            ("failed".to_owned(), ProjectState::Failed("bad manifest".to_owned())),
+        ]);
+        let names_of = |req: &CompareImageReq| compare_projects_of(req, &project_dict).unwrap();
+
+        // the wildcard takes every ready project.
+        let projects = names_of(&mk_req(ALL_PROJECTS, None));
+        assert_eq!(projects, CompareProjects { names: vec!["p".into(), "pd".into()], not_ready: 2, unsuited: 0 });
+
+        // and skips those the weights do not suit.
+        let mut req = mk_req(ALL_PROJECTS, None);
+        req.weights = Some(HashMap::from([(HashType::DHASH, 1.0)]));
+        assert_eq!(names_of(&req), CompareProjects { names: vec!["pd".into()], not_ready: 2, unsuited: 1 });
+
+        // or those the query hashes do not suit.
+        let mut req = mk_req(ALL_PROJECTS, None);
+        req.query_hash = Some(mk_query_hash(&[HashType::PHASH], None));
+        assert_eq!(names_of(&req).names, vec!["p".to_owned(), "pd".to_owned()]);
+        req.query_hash = Some(mk_query_hash(&[HashType::PHASH, HashType::DHASH], None));
+        assert_eq!(names_of(&req).names, vec!["pd".to_owned()]);
+        let hasher_config = HasherConfig { hash_width: 8, hash_height: 8, ..HasherConfig::default() };
+        req.query_hash = Some(mk_query_hash(&[HashType::PHASH], Some(hasher_config)));
+        assert_eq!(names_of(&req), CompareProjects { names: vec![], not_ready: 2, unsuited: 2 });
+
+        // named projects are searched as given, once each, even unknown ones.
+        let projects = names_of(&mk_req("", Some(vec!["pd", "p", "pd", "nope"])));
+        assert_eq!(projects.names, vec!["pd".to_owned(), "p".to_owned(), "nope".to_owned()]);
+
+        // but must be ready.
+        assert!(compare_projects_of(&mk_req("loading", None), &project_dict).is_err());
+        assert!(compare_projects_of(&mk_req("", Some(vec!["p", "failed"])), &project_dict).is_err());
+
+        // and given one way only.
+        assert!(compare_projects_of(&mk_req("", None), &project_dict).is_err());
+        assert!(compare_projects_of(&mk_req("", Some(vec![])), &project_dict).is_err());
+        assert!(compare_projects_of(&mk_req("p", Some(vec!["p"])), &project_dict).is_err());
+
+        std::fs::remove_dir_all(&project_root).unwrap();
+    }
+}
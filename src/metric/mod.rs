@@ -7,12 +7,10 @@ use std::iter::zip;
 /// In this implementation, we use hamming distance.
 impl traits::Metrizable for imagehash::Hash {
     fn dist(&self, other: &Self) -> f64 {
-      let lhs = self.bits.clone();
-      let rhs = other.bits.clone();
-      let hm_diff = zip(lhs.iter(), rhs.iter())
+      let hm_diff = zip(self.bits.iter(), other.bits.iter())
         .map(|(x, y)| x != y)
         .map(|x| if x {1} else {0})
-        .fold(0, |acc, x| acc + x);
+        .sum::<i32>();
       
       hm_diff as f64
     }
@@ -20,15 +18,15 @@ impl traits::Metrizable for imagehash::Hash {
 
 impl traits::BoundedVariation for imagehash::Hash {
     fn min(&self) -> f64 {
-        // The minimum possible difference between two 64-bit
+        // The minimum possible difference between two n-bit
         // arrays, is 0.
         0.0
     }
 
     fn max(&self) -> f64 {
-        // The maximum possible difference between two 64-bit
-        // arrays, is 64.
-        64.0
+        // The maximum possible difference between two n-bit
+        // arrays, is n.
+        self.bits.len() as f64
     }
 
 }
@@ -21,7 +21,7 @@ pub trait BoundedVariation {
   /// 
   fn normalize(&self, value : f64) -> f64 {
     let value = self.clip(value);
-    (value - &self.min()) / (&self.max() - &self.min())
+    (value - self.min()) / (self.max() - self.min())
   }
 }
 
@@ -0,0 +1,470 @@
+//! Per-project index database.
+//!
+//! Hashes of every image of a project live in one append-only log file in
+//! the project folder, instead of one cache file next to every image.
+//!
+//! The log starts with a header, followed by frames of
+//! `[payload length: u32][crc32 of payload: u32][payload]`, each payload
+//! being one bincode encoded `LogEntry`. A crash in the middle of an append
+//! leaves a torn frame at the end, which fails its length or checksum and
+//! is cut off on the next open. The log is compacted by writing a new file
+//! and renaming it over the old one.
+//!
+//! Every record keeps the size, modification time and content digest of
+//! its image, so a record of an image replaced on disk is not trusted.
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::{File, OpenOptions, rename};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::image_hash::{Hash, HashType, HasherConfig, ImageHashEntry};
+
+/// Name of the per-project index database file.
+pub const INDEX_DB_FILE_NAME: &str = ".index.vmdb";
+
+/// Magic and format version at the start of the log.
+const DB_HEADER: [u8; 8] = *b"VMDB0001";
+
+/// Size of `[length][crc32]` ahead of every payload.
+const FRAME_HEADER_SIZE: usize = 8;
+
+/// Compaction is not worth it for fewer dead entries than this.
+const MIN_COMPACT_DEAD: usize = 64;
+
+/// What the index knows about one image.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImageRecord {
+    /// File name of the image in the project folder.
+    pub image_name: String,
+    /// File size in bytes, when hashed.
+    pub size: u64,
+    /// Modification time in nanoseconds since unix epoch, when hashed.
+    pub modified_ns: Option<u64>,
+    /// SHA-256 of the file content in hex, when hashed.
+    pub digest: String,
+    /// The hasher config the hashes were calculated with.
+    pub hasher_config: HasherConfig,
+    pub hashes: BTreeMap<HashType, Hash>,
+}
+
+/// Whether a record still describes the image on disk.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordState {
+    /// Same size and modification time.
+    Fresh,
+    /// Modified, but with the same content, e.g. copied or touched. Holds
+    /// the record with up-to-date metadata.
+    Touched(ImageRecord),
+    /// The image changed, or cannot be checked.
+    Stale,
+}
+
+/// SHA-256 of a file content, in hex.
+pub fn file_digest(path: &Path) -> Result<String, Box<dyn Error>> {
+    let mut f_handle = File::open(path)
+        .map_err(|e| format!("cannot open '{}': {}", path.display(), e))?;
+
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut f_handle, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// SHA-256 of some data in hex, as `file_digest` of a file holding it.
+pub fn data_digest(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Size and modification time of a file, as recorded in the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    pub size: u64,
+    pub modified_ns: Option<u64>,
+}
+
+/// Size and modification time of a file.
+pub fn file_stamp(path: &Path) -> Result<FileStamp, Box<dyn Error>> {
+    let metadata = std::fs::metadata(path)
+        .map_err(|e| format!("cannot stat '{}': {}", path.display(), e))?;
+
+    let modified_ns = metadata.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos() as u64);
+
+    Ok(FileStamp { size: metadata.len(), modified_ns })
+}
+
+impl ImageRecord {
+    /// Make a record of a hashed image, with the current file metadata.
+    pub fn new(entry: &ImageHashEntry, hasher_config: &HasherConfig) -> Result<Self, Box<dyn Error>> {
+        let image_name = entry.image_name.file_name()
+            .ok_or_else(|| format!("invalid image path '{}'", entry.image_name.display()))?
+            .to_string_lossy()
+            .into_owned();
+
+        let FileStamp { size, modified_ns } = file_stamp(&entry.image_name)?;
+
+        Ok(ImageRecord {
+            image_name,
+            size,
+            modified_ns,
+            digest: file_digest(&entry.image_name)?,
+            hasher_config: hasher_config.clone(),
+            hashes: entry.hashes.clone(),
+        })
+    }
+
+    /// Size and modification time of the image when it was recorded.
+    pub fn stamp(&self) -> FileStamp {
+        FileStamp { size: self.size, modified_ns: self.modified_ns }
+    }
+
+    /// Whether the record holds every given hash type, calculated with
+    /// given config.
+    pub fn covers(&self, hash_types: &[HashType], hasher_config: &HasherConfig) -> bool {
+        self.hasher_config == *hasher_config
+            && hash_types.iter().all(|t| self.hashes.contains_key(t))
+    }
+
+    /// Check the record against the image on disk.
+    /// 
+    /// Size and modification time are compared first, the content is only
+    /// read when the modification time differs.
+    pub fn check(&self, image_path: &Path) -> RecordState {
+        let Ok(FileStamp { size, modified_ns }) = file_stamp(image_path) else {
+            return RecordState::Stale;
+        };
+
+        if size != self.size {
+            return RecordState::Stale;
+        }
+        if modified_ns.is_some() && modified_ns == self.modified_ns {
+            return RecordState::Fresh;
+        }
+
+        match file_digest(image_path) {
+            Ok(current) if current == self.digest => 
+                RecordState::Touched(ImageRecord { modified_ns, ..self.clone() }),
+            _ => RecordState::Stale,
+        }
+    }
+}
+
+/// One entry of the log.
+#[derive(Serialize, Deserialize, Debug)]
+enum LogEntry {
+    Put(ImageRecord),
+    Remove(String),
+}
+
+/// Handle of the index database of a project.
+#[derive(Debug)]
+pub struct ProjectDb {
+    file_name: PathBuf,
+    /// Images with a live record, and their stamp.
+    live: HashMap<String, FileStamp>,
+    /// Number of entries in the log, live or not.
+    entries: usize,
+}
+
+/// CRC-32 (IEEE), to tell torn or damaged frames apart.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn encode_frame(entry: &LogEntry, buf: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+    let payload = bincode::serde::encode_to_vec(entry, bincode::config::standard())
+        .map_err(|e| format!("error while serialize ({})", e))?;
+
+    buf.extend((payload.len() as u32).to_le_bytes());
+    buf.extend(crc32(&payload).to_le_bytes());
+    buf.extend(payload);
+    Ok(())
+}
+
+/// Decode frames from the log body, stops at the first torn or damaged
+/// frame. Returns the entries, and the length of the valid part.
+fn decode_frames(data: &[u8]) -> (Vec<LogEntry>, usize) {
+    let mut entries = vec![];
+    let mut pos = 0;
+
+    while data.len() - pos >= FRAME_HEADER_SIZE {
+        let len = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap());
+
+        let Some(payload) = data.get(pos + FRAME_HEADER_SIZE..pos + FRAME_HEADER_SIZE + len) else { break };
+        if crc32(payload) != crc {
+            break;
+        }
+
+        match bincode::serde::decode_from_slice(payload, bincode::config::standard()) {
+            Ok((entry, _)) => entries.push(entry),
+            Err(_) => break,
+        }
+
+        pos += FRAME_HEADER_SIZE + len;
+    }
+
+    (entries, pos)
+}
+
+fn stamps_of(records: &HashMap<String, ImageRecord>) -> HashMap<String, FileStamp> {
+    records.iter().map(|(name, r)| (name.clone(), r.stamp())).collect()
+}
+
+/// Apply log entries in order, returns the live records.
+fn replay(entries: Vec<LogEntry>) -> HashMap<String, ImageRecord> {
+    let mut records = HashMap::new();
+    for entry in entries {
+        match entry {
+            LogEntry::Put(record) => { records.insert(record.image_name.clone(), record); },
+            LogEntry::Remove(image_name) => { records.remove(&image_name); },
+        }
+    }
+    records
+}
+
+impl ProjectDb {
+    /// Open the database of a project, creating it if there is none.
+    ///
+    /// Returns the handle, and the live record of every image.
+    pub fn open(project_path: &Path) -> Result<(Self, HashMap<String, ImageRecord>), Box<dyn Error>> {
+        let file_name = project_path.join(INDEX_DB_FILE_NAME);
+
+        let data = match std::fs::read(&file_name) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(format!("cannot read index '{}': {}", file_name.display(), e).into()),
+        };
+
+        // a new database, or one which crashed before its header was written.
+        if data.len() < DB_HEADER.len() {
+            let mut db = ProjectDb { file_name, live: HashMap::new(), entries: 0 };
+            db.rewrite(&HashMap::new())?;
+            return Ok((db, HashMap::new()));
+        }
+
+        let body = data.strip_prefix(&DB_HEADER)
+            .ok_or_else(|| format!("'{}' is not an index of a supported version", file_name.display()))?;
+        let (entries, valid_len) = decode_frames(body);
+
+        let entry_count = entries.len();
+        let records = replay(entries);
+
+        let mut db = ProjectDb {
+            file_name,
+            live: stamps_of(&records),
+            entries: entry_count,
+        };
+
+        if valid_len < body.len() {
+            println!("[!] dropping {} damaged bytes at the end of '{}'",
+                body.len() - valid_len, db.file_name.display());
+            db.rewrite(&records)?;
+        } else if db.should_compact() {
+            db.rewrite(&records)?;
+        }
+
+        Ok((db, records))
+    }
+
+    /// Number of images with a live record.
+    pub fn len(&self) -> usize {
+        self.live.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live.is_empty()
+    }
+
+    /// Whether an image has a live record.
+    pub fn contains(&self, image_name: &str) -> bool {
+        self.live.contains_key(image_name)
+    }
+
+    /// Stamp of the image when it was recorded.
+    pub fn stamp(&self, image_name: &str) -> Option<FileStamp> {
+        self.live.get(image_name).copied()
+    }
+
+    /// Write records of images, replacing older ones of the same name.
+    pub fn put(&mut self, records: Vec<ImageRecord>) -> Result<(), Box<dyn Error>> {
+        let stamps: Vec<(String, FileStamp)> = records.iter()
+            .map(|r| (r.image_name.clone(), r.stamp()))
+            .collect();
+        self.append(records.into_iter().map(LogEntry::Put).collect())?;
+        self.live.extend(stamps);
+        Ok(())
+    }
+
+    /// Drop records of images, unknown names are ignored.
+    pub fn remove(&mut self, image_names: &[String]) -> Result<(), Box<dyn Error>> {
+        let removed: Vec<String> = image_names.iter()
+            .filter(|name| self.live.contains_key(*name))
+            .cloned()
+            .collect();
+
+        if removed.is_empty() {
+            return Ok(());
+        }
+
+        self.append(removed.iter().cloned().map(LogEntry::Remove).collect())?;
+        for name in removed.iter() {
+            self.live.remove(name);
+        }
+
+        if self.should_compact() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Append entries to the log, and wait until they are on disk.
+    fn append(&mut self, entries: Vec<LogEntry>) -> Result<(), Box<dyn Error>> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf = vec![];
+        for entry in entries.iter() {
+            encode_frame(entry, &mut buf)?;
+        }
+
+        let mut f_handle = OpenOptions::new().append(true).open(&self.file_name)
+            .map_err(|e| format!("cannot open index '{}': {}", self.file_name.display(), e))?;
+        f_handle.write_all(&buf)?;
+        f_handle.sync_data()?;
+
+        self.entries += entries.len();
+        Ok(())
+    }
+
+    fn should_compact(&self) -> bool {
+        let dead = self.entries - self.live.len();
+        dead >= MIN_COMPACT_DEAD && dead > self.live.len()
+    }
+
+    /// Rewrite the log with live records only.
+    pub fn compact(&mut self) -> Result<(), Box<dyn Error>> {
+        let data = std::fs::read(&self.file_name)
+            .map_err(|e| format!("cannot read index '{}': {}", self.file_name.display(), e))?;
+        let body = data.strip_prefix(&DB_HEADER)
+            .ok_or_else(|| format!("'{}' is not an index of a supported version", self.file_name.display()))?;
+
+        let (entries, _) = decode_frames(body);
+        self.rewrite(&replay(entries))
+    }
+
+    /// Replace the log by a new one holding given records.
+    ///
+    /// The new log is written aside and renamed over the old one, so a
+    /// crash leaves either of them, never a mix.
+    fn rewrite(&mut self, records: &HashMap<String, ImageRecord>) -> Result<(), Box<dyn Error>> {
+        let tmp_file_name = self.file_name.with_added_extension("tmp");
+
+        let mut buf = DB_HEADER.to_vec();
+        for record in records.values() {
+            encode_frame(&LogEntry::Put(record.clone()), &mut buf)?;
+        }
+
+        let mut f_handle = File::create(&tmp_file_name)
+            .map_err(|e| format!("cannot create index '{}': {}", tmp_file_name.display(), e))?;
+        f_handle.write_all(&buf)?;
+        f_handle.sync_all()?;
+
+        rename(&tmp_file_name, &self.file_name)?;
+
+        self.live = stamps_of(records);
+        self.entries = records.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_record(image_name: &str, bit: bool) -> ImageRecord {
+        ImageRecord {
+            image_name: image_name.to_owned(),
+            size: 1,
+            modified_ns: Some(2),
+            digest: "0".repeat(64),
+            hasher_config: HasherConfig::default(),
+            hashes: BTreeMap::from([(HashType::PHASH, Hash::from_bits(&[bit, true, false]))]),
+        }
+    }
+
+    #[test]
+    fn test_project_db() {
+        let project_path = std::env::temp_dir().join(format!("vismatch-db-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&project_path).unwrap();
+
+        let (mut db, records) = ProjectDb::open(&project_path).unwrap();
+        assert!(records.is_empty());
+
+        db.put(vec![mk_record("a.png", false), mk_record("b.png", false)]).unwrap();
+        db.put(vec![mk_record("a.png", true)]).unwrap();
+        db.remove(&["b.png".to_owned(), "unknown.png".to_owned()]).unwrap();
+
+        let (db, records) = ProjectDb::open(&project_path).unwrap();
+        assert_eq!(db.len(), 1);
+        assert_eq!(records["a.png"], mk_record("a.png", true));
+
+        // a torn frame at the end is cut off
+        let file_name = project_path.join(INDEX_DB_FILE_NAME);
+        let mut f_handle = OpenOptions::new().append(true).open(&file_name).unwrap();
+        f_handle.write_all(&[42, 0, 0, 0, 1, 2]).unwrap();
+        drop(f_handle);
+
+        let (mut db, records) = ProjectDb::open(&project_path).unwrap();
+        assert_eq!(records.len(), 1);
+
+        // enough dead entries get compacted away
+        for _ in 0..MIN_COMPACT_DEAD {
+            db.put(vec![mk_record("c.png", false)]).unwrap();
+            db.remove(&["c.png".to_owned()]).unwrap();
+        }
+        assert!(db.entries < MIN_COMPACT_DEAD);
+
+        let (_, records) = ProjectDb::open(&project_path).unwrap();
+        assert_eq!(records.keys().collect::<Vec<_>>(), vec!["a.png"]);
+
+        std::fs::remove_dir_all(&project_path).unwrap();
+    }
+
+    #[test]
+    fn test_record_check() {
+        let image_path = std::env::temp_dir().join(format!("vismatch-record-{}.png", uuid::Uuid::new_v4()));
+        std::fs::write(&image_path, b"0123456789").unwrap();
+
+        let entry = ImageHashEntry { image_name: image_path.clone(), hashes: mk_record("", true).hashes };
+        let record = ImageRecord::new(&entry, &HasherConfig::default()).unwrap();
+        assert_eq!(record.check(&image_path), RecordState::Fresh);
+
+        // same content, but touched
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        File::options().write(true).open(&image_path).unwrap().set_modified(later).unwrap();
+        let RecordState::Touched(touched) = record.check(&image_path) else { panic!("expected touched") };
+        assert_eq!(touched.check(&image_path), RecordState::Fresh);
+
+        // replaced with the same size
+        std::fs::write(&image_path, b"9876543210").unwrap();
+        assert_eq!(touched.check(&image_path), RecordState::Stale);
+
+        std::fs::remove_file(&image_path).unwrap();
+        assert_eq!(record.check(&image_path), RecordState::Stale);
+    }
+}
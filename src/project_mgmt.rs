@@ -4,70 +4,1345 @@
 //! concept: take a project path, and returns a vec of image hashing result.
 //! 
 //! The ``
-use std::time::Instant;                // calculate time difference
+use std::time::{Instant, SystemTime};  // calculate time difference
 use std::error::Error;                 // standard error trait
 
-use crate::utils::is_image_file;
+use crate::utils::{is_image_file, par_map};
 
 // functional pattern support for clean code
 use itertools::Itertools;
+use walkdir::WalkDir;      // recursive folder walk
+use image::DynamicImage;   // image IO
 
-use std::path::Path;      // filesystem path operations
-use std::fs::read_dir; // filesystem utils
+use std::path::{Path, PathBuf}; // filesystem path operations
+use std::fs::{read_dir, File, OpenOptions, rename}; // filesystem utils
+use std::io::Write;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::image_hash::{
     ImageHashEntry,
-    //ImageDistEntry,
+    ImageDistEntry,
+    Hash,
     HashType,
-    fetch_cache_or_calc_hash,
+    HashWeights,
+    HasherConfig,
+    HashIndex,
+    SearchLimits,
+    calc_hashes,
+    calc_image_hash,
+    fetch_hash_cache,
+    is_hash_cache_stale,
+    remove_hash_cache,
 };
+use crate::project_db::{data_digest, file_stamp, FileStamp, ImageRecord, ProjectDb, RecordState};
 
-/// Calculate project-wide hash from given path.
-pub fn calc_hash_project(project_path: &Path, hash_type: HashType) -> Result<Vec<ImageHashEntry>, Box<dyn Error>> {
+/// Name of the per-project manifest file, which declares how images of
+/// the project are hashed.
+pub const MANIFEST_FILE_NAME: &str = ".project.json";
+
+/// Project-wide settings, persisted in the project folder.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProjectManifest {
+    /// The primary hash algorithm used by every image of the project.
+    pub hash_type: HashType,
+
+    /// Additional hash algorithms indexed for every image, so distances
+    /// of several hashes can be fused into one score.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_hash_types: Vec<HashType>,
+
+    /// Parameters shared by every hasher of the project.
+    #[serde(default)]
+    pub hasher_config: HasherConfig,
+}
+
+impl ProjectManifest {
+    /// All hash types indexed by the project, primary one first.
+    pub fn hash_types(&self) -> Vec<HashType> {
+        let mut hash_types = vec![self.hash_type];
+        for t in self.extra_hash_types.iter() {
+            if !hash_types.contains(t) {
+                hash_types.push(*t);
+            }
+        }
+        hash_types
+    }
+}
+
+impl Default for ProjectManifest {
+    /// Projects created before manifests existed were hashed with pHash.
+    fn default() -> Self {
+        ProjectManifest { 
+            hash_type: HashType::PHASH, 
+            extra_hash_types: vec![], 
+            hasher_config: HasherConfig::default(),
+        }
+    }
+}
+
+/// A loaded project: its manifest and the index of all its images.
+#[derive(Debug)]
+pub struct Project {
+    pub manifest: ProjectManifest,
+    /// Images of the project, indexed on the primary hash type.
+    pub index: HashIndex,
+    /// Where hashes of the project are persisted.
+    pub db: ProjectDb,
+    /// Image names given to uploads which are not stored yet.
+    pub reserved_names: HashSet<String>,
+}
+
+impl Project {
+    /// Make an empty project with given manifest.
+    pub fn new(manifest: ProjectManifest, db: ProjectDb) -> Self {
+        Project::with_entries(manifest, db, vec![])
+    }
+
+    /// Make a project and index given image hashes.
+    pub fn with_entries(manifest: ProjectManifest, db: ProjectDb, hash_list: Vec<ImageHashEntry>) -> Self {
+        let index = HashIndex::from_entries(manifest.hash_type, hash_list);
+        Project { manifest, index, db, reserved_names: HashSet::new() }
+    }
+
+    /// Index a hashed image and persist its record, an image of the same
+    /// name is replaced.
+    pub fn put_image(&mut self, entry: ImageHashEntry, record: ImageRecord) -> Result<(), Box<dyn Error>> {
+        self.put_images(vec![(entry, record)])
+    }
+
+    /// Index hashed images and persist their records in one write.
+    pub fn put_images(&mut self, hashed: Vec<(ImageHashEntry, ImageRecord)>) -> Result<(), Box<dyn Error>> {
+        let (entries, records): (Vec<ImageHashEntry>, Vec<ImageRecord>) = hashed.into_iter().unzip();
+
+        self.db.put(records)
+            .map_err(|e| format!("cannot persist image hashes: {}", e))?;
+        for entry in entries {
+            self.index.insert(entry)?;
+        }
+        Ok(())
+    }
+
+    /// Move staged images into place and index them, images of the same
+    /// names are replaced.
+    ///
+    /// Their records are persisted first, in one write: if that fails, no
+    /// image is stored and the error is returned. Otherwise, returns
+    /// whether each image is stored, in order. The staged file of an image
+    /// which is not stored is removed.
+    pub fn put_staged_images(&mut self, staged: Vec<StagedImage>) -> Result<Vec<Result<(), String>>, Box<dyn Error>> {
+        let records = staged.iter().map(|s| s.record.clone()).collect();
+
+        if let Err(e) = self.db.put(records) {
+            staged.into_iter().for_each(StagedImage::discard);
+            return Err(format!("cannot persist image hashes: {}", e).into());
+        }
+
+        let mut stored = vec![];
+        for staged in staged {
+            let image_path = staged.entry.image_name.clone();
+            let result = self.index.insert(staged.entry.clone())
+                .and_then(|_| rename(&staged.staging_path, &image_path).map_err(Into::into))
+                .map_err(|e| format!("error while saving image: {}", e));
+
+            if result.is_err() {
+                staged.discard();
+                if let Err(e) = self.remove_image(&image_path) {
+                    println!("[!] cannot drop record of <{}>: {}", image_path.display(), e);
+                }
+            }
+            stored.push(result);
+        }
+
+        Ok(stored)
+    }
+
+    /// Drop an image from the index and its record, returns whether it was
+    /// indexed.
+    pub fn remove_image(&mut self, image_path: &Path) -> Result<bool, Box<dyn Error>> {
+        let removed = self.index.remove(image_path).is_some();
+
+        if let Some(image_name) = image_path.file_name() {
+            self.db.remove(&[image_name.to_string_lossy().into_owned()])
+                .map_err(|e| format!("cannot update project index: {}", e))?;
+        }
+
+        Ok(removed)
+    }
+
+    /// File name of every indexed image, and its size and modification
+    /// time when it was indexed.
+    pub fn image_stamps(&self) -> Vec<(String, Option<FileStamp>)> {
+        self.index.iter()
+            .map(|entry| {
+                let image_name = file_name_of(&entry.image_name);
+                let stamp = self.db.stamp(&image_name);
+                (image_name, stamp)
+            })
+            .collect()
+    }
+
+    /// Whether an image is indexed with the size and modification time it
+    /// has on disk now.
+    pub fn is_up_to_date(&self, image_path: &Path) -> bool {
+        let recorded = image_path.file_name()
+            .and_then(|name| self.db.stamp(&name.to_string_lossy()));
+
+        self.index.get(image_path).is_some()
+            && recorded.is_some_and(|stamp| file_stamp(image_path).is_ok_and(|s| s == stamp))
+    }
+
+    /// Closest indexed image to given hashes, if its normalized distance is
+    /// at most `max_normalized_distance`. Every hash type of the project
+    /// weighs the same, and the `exclude` image is never matched, e.g. an
+    /// image about to be replaced.
+    pub fn find_duplicate(&self, hashes: &BTreeMap<HashType, Hash>, max_normalized_distance: f64, exclude: Option<&Path>)
+        -> Option<ImageDistEntry> {
+
+        let weights = HashWeights::uniform(&self.manifest.hash_types());
+        let limits = SearchLimits {
+            top_k: 2,
+            max_distance: None,
+            max_normalized_distance: Some(max_normalized_distance),
+        };
+
+        self.index.search(hashes, &weights, &limits).into_iter()
+            .find(|entry| exclude.is_none_or(|path| entry.image_name != path))
+    }
+}
+
+/// Images of a project to resync with the disk, see `Project::plan_rescan`.
+#[derive(Debug, Default)]
+pub struct RescanPlan {
+    /// Images to hash, new or changed on disk.
+    pub to_hash: Vec<PathBuf>,
+    /// Indexed images which are gone from disk.
+    pub gone: Vec<PathBuf>,
+    /// Number of images indexed as they are on disk.
+    pub unchanged: usize,
+}
+
+/// What a rescan changed in a project, by image file name.
+#[derive(Debug, Default)]
+pub struct RescanSummary {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+    pub unchanged: usize,
+    /// Images which could not be hashed, with the reason.
+    pub failed: Vec<(String, String)>,
+}
+
+fn file_name_of(image_path: &Path) -> String {
+    image_path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+}
+
+impl Project {
+    /// Compare the index with the images on disk.
+    ///
+    /// Images whose size or modification time differ from their record are
+    /// hashed again, every image is with `force`.
+    pub fn plan_rescan(&self, image_paths: Vec<PathBuf>, force: bool) -> RescanPlan {
+        let on_disk: HashSet<&Path> = image_paths.iter().map(PathBuf::as_path).collect();
+        let gone = self.index.iter()
+            .filter(|entry| !on_disk.contains(entry.image_name.as_path()))
+            .map(|entry| entry.image_name.clone())
+            .collect();
+
+        let (to_hash, fresh): (Vec<PathBuf>, Vec<PathBuf>) = image_paths.into_iter()
+            .partition(|image_path| force || !self.is_up_to_date(image_path));
+
+        RescanPlan { to_hash, gone, unchanged: fresh.len() }
+    }
+
+    /// Apply a rescan: index the hashed images, and drop the gone ones.
+    ///
+    /// The disk may change while images are hashed, so an image is only
+    /// dropped if it is still gone.
+    pub fn apply_rescan(&mut self, plan: RescanPlan, hashed: Vec<Result<(ImageHashEntry, ImageRecord), String>>)
+        -> Result<RescanSummary, Box<dyn Error>> {
+
+        let mut summary = RescanSummary { unchanged: plan.unchanged, ..RescanSummary::default() };
+        let mut to_put = vec![];
+
+        for (image_path, result) in plan.to_hash.iter().zip(hashed) {
+            let image_name = file_name_of(image_path);
+            match result {
+                Ok((entry, record)) => {
+                    match self.index.get(image_path) {
+                        None => summary.added.push(image_name),
+                        Some(old) if old.hashes == entry.hashes => summary.unchanged += 1,
+                        Some(_) => summary.changed.push(image_name),
+                    }
+                    to_put.push((entry, record));
+                },
+                Err(err) => summary.failed.push((image_name, err)),
+            }
+        }
+        self.put_images(to_put)?;
+
+        for image_path in plan.gone.iter().filter(|p| !p.is_file()) {
+            if self.remove_image(image_path)? {
+                summary.removed.push(file_name_of(image_path));
+            }
+        }
+
+        Ok(summary)
+    }
+}
+
+/// A group of near-duplicate images, see `duplicate_clusters`.
+#[derive(Debug, Clone, Default)]
+pub struct DuplicateCluster {
+    /// File names of the images, sorted.
+    pub members: Vec<String>,
+    /// Pairs of near-duplicates which link the members, as indices into
+    /// `members`.
+    pub distances: Vec<MemberDistance>,
+}
+
+/// Fused distance between two members of a cluster.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemberDistance {
+    pub a: usize,
+    pub b: usize,
+    pub distance: f64,
+    /// The distance scaled to [0, 1] by hash bounds.
+    pub norm_distance: f64,
+}
+
+/// Root of the set of `i` in a union-find forest, halving paths on the way.
+fn find_root(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+    }
+    i
+}
+
+/// Group the images of an index into clusters of near-duplicates.
+///
+/// Two images are near-duplicates when their fused distance, once
+/// normalized, is at most `max_normalized_distance`. A cluster holds
+/// every image linked by near-duplicates, so two members may be farther
+/// apart when other members are close to both. Images without any
+/// near-duplicate are left out.
+///
+/// Images are searched in name order, and the search stops once
+/// `max_links` pairs of near-duplicates are found: clusters then only hold
+/// the images linked so far, and images searched later may be left out.
+/// Also returns whether the search stopped early.
+///
+/// Clusters come largest first.
+pub fn duplicate_clusters(index: &HashIndex, weights: &HashWeights, max_normalized_distance: f64, max_links: usize)
+    -> (Vec<DuplicateCluster>, bool) {
+
+    let entries: Vec<&ImageHashEntry> = index.iter().sorted_by_key(|entry| &entry.image_name).collect();
+    let slots: HashMap<&Path, usize> = entries.iter().enumerate()
+        .map(|(i, entry)| (entry.image_name.as_path(), i))
+        .collect();
+
+    let limits = SearchLimits {
+        top_k: entries.len(),
+        max_distance: None,
+        max_normalized_distance: Some(max_normalized_distance),
+    };
+
+    // link every image to its near-duplicates, each pair is found from
+    // both ends and kept once. A pair past the cap stops the search.
+    let mut parent: Vec<usize> = (0..entries.len()).collect();
+    let mut links: Vec<(usize, usize, ImageDistEntry)> = vec![];
+    let mut truncated = false;
+    'search: for (i, entry) in entries.iter().enumerate() {
+        for near in index.search(&entry.hashes, weights, &limits) {
+            let Some(&j) = slots.get(near.image_name.as_path()) else { continue };
+            if j <= i {
+                continue;
+            }
+            if links.len() == max_links {
+                truncated = true;
+                break 'search;
+            }
+
+            let (root_i, root_j) = (find_root(&mut parent, i), find_root(&mut parent, j));
+            parent[root_i.max(root_j)] = root_i.min(root_j);
+            links.push((i, j, near));
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..entries.len() {
+        groups.entry(find_root(&mut parent, i)).or_default().push(i);
+    }
+
+    // members of a group are in entry order, so sorted by name.
+    let mut member_of: Vec<usize> = vec![0; entries.len()];
+    for group in groups.values() {
+        for (k, &i) in group.iter().enumerate() {
+            member_of[i] = k;
+        }
+    }
+
+    let mut group_links: HashMap<usize, Vec<MemberDistance>> = HashMap::new();
+    for (i, j, near) in links {
+        group_links.entry(find_root(&mut parent, i)).or_default().push(MemberDistance {
+            a: member_of[i],
+            b: member_of[j],
+            distance: near.distance,
+            norm_distance: near.norm_distance,
+        });
+    }
+
+    let clusters = groups.into_iter()
+        .filter(|(_, group)| group.len() > 1)
+        .map(|(root, group)| DuplicateCluster {
+            members: group.iter().map(|&i| file_name_of(&entries[i].image_name)).collect(),
+            distances: group_links.remove(&root).unwrap_or_default(),
+        })
+        .sorted_by(|x, y| y.members.len().cmp(&x.members.len()).then_with(|| x.members.cmp(&y.members)))
+        .collect();
+
+    (clusters, truncated)
+}
+
+/// Hash images of a project on a pool of `workers` threads, in order.
+pub fn hash_project_images(image_paths: &[PathBuf], manifest: &ProjectManifest, workers: usize)
+    -> Vec<Result<(ImageHashEntry, ImageRecord), String>> {
+
+    par_map(image_paths, workers, |image_path| {
+        hash_project_image(image_path, manifest).map_err(|e| e.to_string())
+    })
+}
+
+/// An image written to a hidden file next to where it is stored, with its
+/// hashes and record, until it is put in its project.
+#[derive(Debug)]
+pub struct StagedImage {
+    /// Hashes of the image, at the path it is stored to.
+    pub entry: ImageHashEntry,
+    pub record: ImageRecord,
+    staging_path: PathBuf,
+}
+
+impl StagedImage {
+    /// Give up the image, and remove its staged file.
+    pub fn discard(self) {
+        if let Err(e) = std::fs::remove_file(&self.staging_path) {
+            println!("[!] cannot remove staged image <{}>: {}", self.staging_path.display(), e);
+        }
+    }
+}
+
+/// Encode an image in the format of `image_path`, hash what is encoded
+/// with the hash types and config of a project, and stage it next to
+/// `image_path`. The staged file is not named like an image, so it is not
+/// taken for one of the project.
+pub fn stage_project_image(image_path: &Path, image: &DynamicImage, manifest: &ProjectManifest)
+    -> Result<StagedImage, Box<dyn Error>> {
+
+    let image_name = file_name_of(image_path);
+    let image_data = crate::image_to_file_bytes(image, image_path)
+        .map_err(|e| format!("error while encoding image: {}", e))?;
+    let stored_image = image::load_from_memory(&image_data)
+        .map_err(|e| format!("error while encoding image: {}", e))?;
+    let hashes = calc_hashes(&stored_image, &manifest.hash_types(), &manifest.hasher_config);
+
+    // the stamp of the staged file is kept by the rename into place.
+    let staging_path = image_path.with_file_name(format!(".{}.{}.staged", image_name, uuid::Uuid::new_v4()));
+    let stamp = File::create(&staging_path)
+        .and_then(|mut f_handle| {
+            f_handle.write_all(&image_data)?;
+            f_handle.sync_data()
+        })
+        .map_err(|e| -> Box<dyn Error> { format!("error while saving image: {}", e).into() })
+        .and_then(|_| file_stamp(&staging_path));
+
+    let FileStamp { size, modified_ns } = match stamp {
+        Ok(stamp) => stamp,
+        Err(e) => {
+            let _ = std::fs::remove_file(&staging_path);
+            return Err(e);
+        },
+    };
+
+    let record = ImageRecord {
+        image_name,
+        size,
+        modified_ns,
+        digest: data_digest(&image_data),
+        hasher_config: manifest.hasher_config.clone(),
+        hashes: hashes.clone(),
+    };
+    let entry = ImageHashEntry { image_name: image_path.to_owned(), hashes };
+
+    Ok(StagedImage { entry, record, staging_path })
+}
+
+/// Stage images of a project folder, on a pool of `workers` threads. The
+/// `i`-th image is named `image_names[i]`, and decoded by `load(i)` on the
+/// workers too.
+///
+/// Returns every staged image, in order.
+pub fn stage_project_images<F>(project_path: &Path, manifest: &ProjectManifest, image_names: &[String], workers: usize, load: F)
+    -> Vec<Result<StagedImage, String>>
+where
+    F: Fn(usize) -> Result<DynamicImage, Box<dyn Error>> + Sync,
+{
+    let indices: Vec<usize> = (0..image_names.len()).collect();
+
+    par_map(&indices, workers, |&i| {
+        let image = load(i).map_err(|e| format!("cannot decode image: {}", e))?;
+
+        stage_project_image(&project_path.join(&image_names[i]), &image, manifest).map_err(|e| e.to_string())
+    })
+}
+
+/// Where a project is in its lifecycle.
+/// 
+/// Projects are loaded in background on startup, so they may not be
+/// ready to serve yet.
+#[derive(Debug)]
+pub enum ProjectState {
+    /// Images are being hashed.
+    Loading(Arc<HashProgress>),
+    /// The project is served, boxed as it outweighs the other states.
+    Ready(Box<Project>),
+    /// The project cannot be loaded, with the reason.
+    Failed(String),
+}
+
+impl ProjectState {
+    /// The project, if it is ready.
+    pub fn ready(&self) -> Option<&Project> {
+        match self {
+            ProjectState::Ready(project) => Some(project.as_ref()),
+            _ => None,
+        }
+    }
+
+    /// The project, if it is ready.
+    pub fn ready_mut(&mut self) -> Option<&mut Project> {
+        match self {
+            ProjectState::Ready(project) => Some(project.as_mut()),
+            _ => None,
+        }
+    }
+}
+
+/// Progress of hashing images, shared between hashing workers and
+/// whoever reports it.
+#[derive(Debug, Default)]
+pub struct HashProgress {
+    total: AtomicUsize,
+    done: AtomicUsize,
+}
+
+impl HashProgress {
+    pub fn new() -> Self {
+        HashProgress::default()
+    }
+
+    /// Number of images to hash.
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Number of images hashed, or failed to.
+    pub fn done(&self) -> usize {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    fn add_total(&self, n: usize) {
+        self.total.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Count one image as done, returns the new count.
+    fn inc(&self) -> usize {
+        self.done.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// List image files of a project folder.
+pub fn list_project_images(project_path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
     let project_dir_reader = 
         read_dir(project_path)
             .map_err(|e: std::io::Error| format!("error reading project folder: <{}>", e))?;
 
     let (images_in_project, _): (Vec<_>, Vec<_>) = 
-        project_dir_reader.filter_ok(|f| is_image_file(f))
+        project_dir_reader.filter_ok(is_image_file)
                 .map_ok(|f| f.path())
                 .partition_result();
 
-    let (h, _): (Vec<_>, Vec<_>) = images_in_project.into_iter()
-                                    .map(|f| fetch_cache_or_calc_hash(
-                                            &f, 
-                                            hash_type, 
-                                            false))
-                                    .partition_result();
-    Ok(h)
+    Ok(images_in_project)
+}
+
+/// Space taken by a project folder, and when anything in it last changed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiskUsage {
+    /// Total size of the files in bytes, index and manifest included.
+    pub size: u64,
+    pub last_modified: Option<SystemTime>,
+}
+
+/// Measure a project folder, files which vanish meanwhile are skipped.
+pub fn project_disk_usage(project_path: &Path) -> Result<DiskUsage, Box<dyn Error>> {
+    let metadata = std::fs::metadata(project_path)
+        .map_err(|e| format!("cannot stat project folder: <{}>", e))?;
+
+    let mut usage = DiskUsage { size: 0, last_modified: metadata.modified().ok() };
+
+    for metadata in WalkDir::new(project_path).min_depth(1).into_iter()
+        .filter_map(|f| f.ok()?.metadata().ok()) {
+
+        if metadata.is_file() {
+            usage.size += metadata.len();
+        }
+        usage.last_modified = usage.last_modified.max(metadata.modified().ok());
+    }
+
+    Ok(usage)
 }
 
-/// For all images in project folder, try to load hash cache file,
-/// and calculate if not found hash cache.
-pub fn load_or_calc_project_hashes(project_path: &Path, hash_type: HashType) 
-    -> Result<Vec<ImageHashEntry>, Box<dyn Error>> {
+/// Hash entry of an image, and a record to write if the index does not
+/// have an up-to-date one.
+type LoadedImage = (ImageHashEntry, Option<ImageRecord>);
+
+/// Hashes of one image of a project: from its record in project index,
+/// from sidecar cache files written before the index existed, or freshly
+/// calculated.
+/// 
+/// Records and sidecar caches are only trusted if the image did not change
+/// since it was hashed.
+fn load_or_calc_image(image_path: &Path, record: Option<ImageRecord>, manifest: &ProjectManifest)
+    -> Result<LoadedImage, Box<dyn Error>> {
+
+    let (hash_types, config) = (manifest.hash_types(), &manifest.hasher_config);
+
+    if let Some(record) = record
+        && record.covers(&hash_types, config) {
+        let mk_entry = |hashes| ImageHashEntry { image_name: image_path.to_owned(), hashes };
+
+        match record.check(image_path) {
+            RecordState::Fresh => return Ok((mk_entry(record.hashes), None)),
+            RecordState::Touched(touched) => return Ok((mk_entry(touched.hashes.clone()), Some(touched))),
+            RecordState::Stale => println!("[*] <{}> changed since hashed, rehashing", image_path.display()),
+        }
+    }
+
+    // import sidecar caches only if they have every hash type, and are newer than the image.
+    let cached: Option<BTreeMap<HashType, _>> = hash_types.iter()
+        .map(|&t| match is_hash_cache_stale(image_path, t, config) {
+            true => None,
+            false => fetch_hash_cache(image_path, t, config).ok().map(|h| (t, h)),
+        })
+        .collect();
+
+    let entry = match cached {
+        Some(hashes) => ImageHashEntry { image_name: image_path.to_owned(), hashes },
+        None => calc_image_hash(image_path, &hash_types, config)?,
+    };
+
+    let record = ImageRecord::new(&entry, config)?;
+    Ok((entry, Some(record)))
+}
+
+/// Hash an image with the hash types and config of a project, and make
+/// its record.
+pub fn hash_project_image(image_path: &Path, manifest: &ProjectManifest)
+    -> Result<(ImageHashEntry, ImageRecord), Box<dyn Error>> {
+
+    let entry = calc_image_hash(image_path, &manifest.hash_types(), &manifest.hasher_config)?;
+    let record = ImageRecord::new(&entry, &manifest.hasher_config)?;
+    Ok((entry, record))
+}
+
+/// Persist new records of a loaded project, forget images which are gone,
+/// and drop sidecar caches which are now in the index.
+fn sync_project_db(db: &mut ProjectDb, manifest: &ProjectManifest, new_records: Vec<(PathBuf, ImageRecord)>, gone: Vec<String>)
+    -> Result<(), Box<dyn Error>> {
+
+    let (image_paths, records): (Vec<PathBuf>, Vec<ImageRecord>) = new_records.into_iter().unzip();
+    db.put(records)?;
+    db.remove(&gone)?;
+
+    for image_path in image_paths {
+        for hash_type in manifest.hash_types() {
+            remove_hash_cache(&image_path, hash_type, &manifest.hasher_config).ok();
+        }
+    }
+    Ok(())
+}
+
+/// Load every given project: read its index database, and hash the images
+/// which are not indexed yet.
+/// 
+/// Images are hashed with every hash type and the hasher config declared
+/// in their project manifest. Images of all projects share one pool of
+/// `workers` threads, so a few large projects keep every worker as busy
+/// as many small ones.
+/// 
+/// `progress[i]` follows the images of `projects[i]`, and `on_loaded` is
+/// called with the index of a project as soon as all of its images are
+/// done, possibly from a worker thread.
+pub fn load_projects<F>(
+    projects: &[(PathBuf, ProjectManifest)], 
+    workers: usize, 
+    progress: &[Arc<HashProgress>],
+    on_loaded: F)
+    where F: Fn(usize, Result<Project, Box<dyn Error>>) + Sync {
 
     let load_now = Instant::now(); // Measure load time
-    
+
+    // one job per image: (index of project, image path)
+    let mut jobs: Vec<(usize, PathBuf)> = vec![];
+    let mut remaining: Vec<AtomicUsize> = vec![];
+    let mut dbs: Vec<Mutex<Option<ProjectDb>>> = vec![];
+    let mut records: Vec<Mutex<HashMap<String, ImageRecord>>> = vec![];
+
+    for (i, (project_path, _)) in projects.iter().enumerate() {
+        let opened = list_project_images(project_path)
+            .and_then(|images| ProjectDb::open(project_path).map(|(db, r)| (images, db, r)));
+
+        let (images, db, project_records) = match opened {
+            Ok(opened) => opened,
+            Err(err) => {
+                remaining.push(AtomicUsize::new(0));
+                dbs.push(Mutex::new(None));
+                records.push(Mutex::new(HashMap::new()));
+                on_loaded(i, Err(err));
+                continue;
+            },
+        };
+
+        progress[i].add_total(images.len());
+        remaining.push(AtomicUsize::new(images.len()));
+        dbs.push(Mutex::new(Some(db)));
+        records.push(Mutex::new(project_records));
+
+        jobs.extend(images.into_iter().map(|f| (i, f)));
+    }
+
+    let hashed: Vec<Mutex<Vec<LoadedImage>>> = 
+        projects.iter().map(|_| Mutex::new(vec![])).collect();
+
+    // called once all images of a project are done.
+    let finish_project = |i: usize| {
+        let (project_path, manifest) = &projects[i];
+        let Some(mut db) = dbs[i].lock().unwrap().take() else { return };
+
+        let (hash_list, new_records): (Vec<ImageHashEntry>, Vec<Option<(PathBuf, ImageRecord)>>) = 
+            std::mem::take(&mut *hashed[i].lock().unwrap()).into_iter()
+                .map(|(entry, record)| {
+                    let image_path = entry.image_name.clone();
+                    (entry, record.map(|r| (image_path, r)))
+                })
+                .unzip();
+        let new_records: Vec<(PathBuf, ImageRecord)> = new_records.into_iter().flatten().collect();
+
+        // records left over have no image anymore.
+        let gone: Vec<String> = records[i].lock().unwrap().drain().map(|(name, _)| name).collect();
+
+        // the index is only a cache, the project is still usable without it.
+        if let Err(err) = sync_project_db(&mut db, manifest, new_records, gone) {
+            println!("[!] cannot update index of project <{:?}>: {}", project_path.file_name().unwrap_or_default(), err);
+        }
+
+        // Verbose
+        println!("[v] loaded {} entries from project <{:?}> ({:?})", 
+            hash_list.len(), project_path.file_name().unwrap_or_default(), manifest.hash_types());
+
+        on_loaded(i, Ok(Project::with_entries(manifest.clone(), db, hash_list)));
+    };
+
+    // nothing to hash, ready at once.
+    for (i, r) in remaining.iter().enumerate() {
+        if r.load(Ordering::Relaxed) == 0 {
+            finish_project(i);
+        }
+    }
+
+    // report roughly every 5%
+    let total = jobs.len();
+    let report_step = (total / 20).max(1);
+    let done = AtomicUsize::new(0);
+
+    println!("[*] hashing {} images of {} projects with {} workers", total, projects.len(), workers);
+
+    par_map(&jobs, workers, |(i, f)| {
+        let (_, manifest) = &projects[*i];
+
+        let record = f.file_name()
+            .and_then(|name| records[*i].lock().unwrap().remove(name.to_string_lossy().as_ref()));
+
+        match load_or_calc_image(f, record, manifest) {
+            Ok(h) => hashed[*i].lock().unwrap().push(h),
+            Err(err) => println!("[!] cannot hash <{}>: {}", f.display(), err),
+        }
+
+        progress[*i].inc();
+        let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+        if done.is_multiple_of(report_step) || done == total {
+            println!("[*] hashed {}/{} images", done, total);
+        }
+
+        // the last image of its project
+        if remaining[*i].fetch_sub(1, Ordering::AcqRel) == 1 {
+            finish_project(*i);
+        }
+    });
+
+    let load_done = load_now.elapsed(); // Measure load time
+
+    println!("[*] loading {} projects costs: {:.3?}", projects.len(), load_done);
+}
+
+/// Same as `load_projects`, for a single project.
+pub fn load_project(project_path: &Path, manifest: &ProjectManifest, workers: usize) 
+    -> Result<Project, Box<dyn Error>> {
+
     // Initial check
     project_path.is_dir()
-        .then(|| ())
+        .then_some(())
         .ok_or_else( || 
             format!("failed to access project path {:?}", project_path))?;
 
-    let project_name = 
-        project_path.file_name().ok_or("invalid project name")?;
+    let projects = [(project_path.to_owned(), manifest.clone())];
+    let result: Mutex<Option<Result<Project, String>>> = Mutex::new(None);
 
-    // NOTE: Change standard hash type if needed.
-    let hash_list: Vec<ImageHashEntry> = 
-        calc_hash_project(project_path, hash_type)?;
+    load_projects(&projects, workers, &[Arc::new(HashProgress::new())], |_, res| {
+        *result.lock().unwrap() = Some(res.map_err(|e| e.to_string()));
+    });
 
-    let load_done = load_now.elapsed(); // Measure load time
+    Ok(result.into_inner().unwrap().ok_or("no result of project")??)
+}
+
+/// Write a json file by writing a temporary file first, then renaming it over
+/// the old one, so a crash never leaves a half-written file.
+fn write_json_file<T: serde::Serialize>(file_name: &Path, value: &T) -> Result<(), Box<dyn Error>> {
+
+    let tmp_file_name = file_name.with_added_extension("tmp");
+
+    let f_handle = File::create(&tmp_file_name)
+        .map_err(|e| format!("cannot create file '{}': {}", tmp_file_name.display(), e))?;
+
+    serde_json::to_writer_pretty(f_handle, value)
+        .map_err(|e| format!("cannot write file '{}': {}", tmp_file_name.display(), e))?;
+
+    rename(&tmp_file_name, file_name)?;
+
+    Ok(())
+}
+
+/// Load the manifest of a project, if there is one.
+pub fn load_project_manifest(project_path: &Path) -> Result<Option<ProjectManifest>, Box<dyn Error>> {
+
+    let manifest_file_name = project_path.join(MANIFEST_FILE_NAME);
+
+    if !manifest_file_name.exists() {
+        return Ok(None);
+    }
+
+    let f_handle = File::open(&manifest_file_name)
+        .map_err(|e| format!("cannot open manifest '{}': {}", manifest_file_name.display(), e))?;
+
+    let manifest: ProjectManifest = serde_json::from_reader(f_handle)
+        .map_err(|e| format!("cannot parse manifest '{}': {}", manifest_file_name.display(), e))?;
+
+    Ok(Some(manifest))
+}
+
+/// Persist the manifest of a project.
+pub fn write_project_manifest(project_path: &Path, manifest: &ProjectManifest) -> Result<(), Box<dyn Error>> {
+    write_json_file(&project_path.join(MANIFEST_FILE_NAME), manifest)
+}
+
+/// Load the manifest of a project, and write the default one for projects
+/// which do not have a manifest yet.
+pub fn load_or_init_project_manifest(project_path: &Path) -> Result<ProjectManifest, Box<dyn Error>> {
+    match load_project_manifest(project_path)? {
+        Some(manifest) => Ok(manifest),
+        None => {
+            let manifest = ProjectManifest::default();
+            write_project_manifest(project_path, &manifest)?;
+            Ok(manifest)
+        },
+    }
+}
+
+/// Name of the per-project file which keeps image deletion tokens.
+///
+/// It starts with a dot and is not an image, so project scanning never
+/// picks it up.
+pub const TOKEN_FILE_NAME: &str = ".tokens.log";
+
+/// Dropped tokens a token log holds at least before it is compacted.
+const MIN_COMPACT_DEAD_TOKENS: usize = 64;
+
+/// Mint a new, unique token for removing an uploaded image.
+pub fn mk_deletion_token() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// One line of a token log: a token minted for an image, or dropped.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct TokenLogLine {
+    token: String,
+    /// `None` once the token is dropped.
+    image_name: Option<String>,
+}
+
+/// Deletion tokens of a project.
+///
+/// Tokens are persisted as an append-only log of json lines, so minting
+/// or dropping a token only writes that token. The log is rewritten once
+/// it holds more dropped tokens than live ones.
+#[derive(Debug)]
+pub struct ProjectTokens {
+    file_name: PathBuf,
+    /// Token -> image file name.
+    tokens: HashMap<String, String>,
+    /// Image file name -> its tokens.
+    by_image: HashMap<String, Vec<String>>,
+    /// Lines in the log, live or not.
+    lines: usize,
+}
+
+impl ProjectTokens {
+    /// Load the tokens of a project.
+    ///
+    /// A project without token file simply has no tokens yet.
+    pub fn open(project_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file_name = project_path.join(TOKEN_FILE_NAME);
+
+        let data = match std::fs::read_to_string(&file_name) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("cannot read token file '{}': {}", file_name.display(), e).into()),
+        };
+
+        let mut project_tokens = ProjectTokens {
+            file_name,
+            tokens: HashMap::new(),
+            by_image: HashMap::new(),
+            lines: 0,
+        };
+
+        // a line cut by a crash is dropped, and the log written again.
+        let mut damaged = false;
+        for line in data.lines() {
+            match serde_json::from_str::<TokenLogLine>(line) {
+                Ok(TokenLogLine { token, image_name: Some(image_name) }) => project_tokens.insert(token, image_name),
+                Ok(TokenLogLine { token, image_name: None }) => project_tokens.drop_token(&token),
+                Err(_) => damaged = true,
+            }
+            project_tokens.lines += 1;
+        }
+
+        if damaged {
+            println!("[!] dropping damaged lines of '{}'", project_tokens.file_name.display());
+            project_tokens.rewrite()?;
+        } else if project_tokens.should_compact() {
+            project_tokens.rewrite()?;
+        }
+
+        Ok(project_tokens)
+    }
+
+    /// Number of live tokens.
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// The image file name a token deletes.
+    pub fn image_of(&self, token: &str) -> Option<&str> {
+        self.tokens.get(token).map(String::as_str)
+    }
+
+    /// Mint a token per image, returned in the order of `image_names`.
+    pub fn mint(&mut self, image_names: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+        let tokens: Vec<String> = image_names.iter().map(|_| mk_deletion_token()).collect();
+
+        self.append(tokens.iter().zip(image_names)
+            .map(|(token, image_name)| TokenLogLine { token: token.clone(), image_name: Some(image_name.clone()) })
+            .collect())?;
+        for (token, image_name) in tokens.iter().zip(image_names) {
+            self.insert(token.clone(), image_name.clone());
+        }
+        Ok(tokens)
+    }
+
+    /// Drop given tokens, unknown ones are ignored.
+    pub fn remove(&mut self, tokens: &[String]) -> Result<(), Box<dyn Error>> {
+        let removed: Vec<String> = tokens.iter()
+            .filter(|token| self.tokens.contains_key(*token))
+            .cloned()
+            .collect();
+
+        if removed.is_empty() {
+            return Ok(());
+        }
+
+        self.append(removed.iter()
+            .map(|token| TokenLogLine { token: token.clone(), image_name: None })
+            .collect())?;
+        for token in removed.iter() {
+            self.drop_token(token);
+        }
 
-    // Verbose
+        if self.should_compact() {
+            self.rewrite()?;
+        }
+        Ok(())
+    }
 
-    println!("[*] loading project <{:?}> costs: {:.3?}", project_name, load_done);
-    println!("[v] loaded {} entries from project <{:?}>", hash_list.len(), project_name);
-    
-    Ok(hash_list)
-}
\ No newline at end of file
+    /// Drop the tokens of images which are gone.
+    pub fn forget_images(&mut self, image_names: &[String]) -> Result<(), Box<dyn Error>> {
+        let tokens: Vec<String> = image_names.iter()
+            .filter_map(|image_name| self.by_image.get(image_name))
+            .flatten()
+            .cloned()
+            .collect();
+        self.remove(&tokens)
+    }
+
+    fn insert(&mut self, token: String, image_name: String) {
+        self.by_image.entry(image_name.clone()).or_default().push(token.clone());
+        self.tokens.insert(token, image_name);
+    }
+
+    fn drop_token(&mut self, token: &str) {
+        let Some(image_name) = self.tokens.remove(token) else { return };
+        if let Some(tokens) = self.by_image.get_mut(&image_name) {
+            tokens.retain(|t| t != token);
+            if tokens.is_empty() {
+                self.by_image.remove(&image_name);
+            }
+        }
+    }
+
+    /// Append lines to the log.
+    fn append(&mut self, lines: Vec<TokenLogLine>) -> Result<(), Box<dyn Error>> {
+        let mut buf = String::new();
+        for line in lines.iter() {
+            buf.push_str(&serde_json::to_string(line)?);
+            buf.push('\n');
+        }
+
+        let mut f_handle = OpenOptions::new().create(true).append(true).open(&self.file_name)
+            .map_err(|e| format!("cannot open token file '{}': {}", self.file_name.display(), e))?;
+        f_handle.write_all(buf.as_bytes())?;
+
+        self.lines += lines.len();
+        Ok(())
+    }
+
+    fn should_compact(&self) -> bool {
+        let dead = self.lines - self.tokens.len();
+        dead >= MIN_COMPACT_DEAD_TOKENS && dead > self.tokens.len()
+    }
+
+    /// Replace the log by one holding live tokens only, written aside and
+    /// renamed over the old one.
+    fn rewrite(&mut self) -> Result<(), Box<dyn Error>> {
+        let tmp_file_name = self.file_name.with_added_extension("tmp");
+
+        let mut buf = String::new();
+        for (token, image_name) in self.tokens.iter() {
+            let line = TokenLogLine { token: token.clone(), image_name: Some(image_name.clone()) };
+            buf.push_str(&serde_json::to_string(&line)?);
+            buf.push('\n');
+        }
+
+        std::fs::write(&tmp_file_name, buf)
+            .map_err(|e| format!("cannot write token file '{}': {}", tmp_file_name.display(), e))?;
+        rename(&tmp_file_name, &self.file_name)?;
+
+        self.lines = self.tokens.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_entry(image_name: &str, bits: &[bool]) -> ImageHashEntry {
+        ImageHashEntry {
+            image_name: PathBuf::from(image_name),
+            hashes: BTreeMap::from([(HashType::PHASH, Hash::from_bits(bits))]),
+        }
+    }
+
+    #[test]
+    fn test_duplicate_clusters() {
+        let project_path = std::env::temp_dir().join(format!("vismatch-mgmt-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&project_path).unwrap();
+        let (db, _) = ProjectDb::open(&project_path).unwrap();
+
+        let (o, i) = (false, true);
+        let entries = vec![
+            mk_entry("c.png", &[o, o, o, o, o, o, i, i]),
+            mk_entry("a.png", &[o, o, o, o, o, o, o, o]),
+            mk_entry("e.png", &[i, i, i, i, i, i, i, o]),
+            mk_entry("b.png", &[o, o, o, o, o, o, o, i]),
+            mk_entry("f.png", &[o, i, o, i, o, i, o, i]),
+            mk_entry("d.png", &[i, i, i, i, i, i, i, i]),
+        ];
+        let project = Project::with_entries(ProjectManifest::default(), db, entries);
+        let weights = HashWeights::uniform(&[HashType::PHASH]);
+
+        // a and c are only linked through b.
+        let (clusters, truncated) = duplicate_clusters(&project.index, &weights, 1.0 / 8.0, 100);
+        assert!(!truncated);
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].members, ["a.png", "b.png", "c.png"]);
+        assert_eq!(clusters[0].distances, [
+            MemberDistance { a: 0, b: 1, distance: 1.0, norm_distance: 0.125 },
+            MemberDistance { a: 1, b: 2, distance: 1.0, norm_distance: 0.125 },
+        ]);
+        assert_eq!(clusters[1].members, ["d.png", "e.png"]);
+        assert_eq!(clusters[1].distances.len(), 1);
+
+        // the search stops at the cap, so later images are left out.
+        let (clusters, truncated) = duplicate_clusters(&project.index, &weights, 1.0 / 8.0, 1);
+        assert!(truncated);
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].members, ["a.png", "b.png"]);
+        assert_eq!(clusters[0].distances.len(), 1);
+
+        // a cap which is just reached does not stop it.
+        assert!(!duplicate_clusters(&project.index, &weights, 1.0 / 8.0, 3).1);
+
+        assert!(duplicate_clusters(&project.index, &weights, 0.0, 100).0.is_empty());
+
+        std::fs::remove_dir_all(&project_path).unwrap();
+    }
+
+    #[test]
+    fn test_find_duplicate() {
+        let project_path = std::env::temp_dir().join(format!("vismatch-mgmt-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&project_path).unwrap();
+        let (db, _) = ProjectDb::open(&project_path).unwrap();
+
+        let (o, i) = (false, true);
+        let entries = vec![
+            mk_entry("a.png", &[o, o, o, o, o, o, o, o]),
+            mk_entry("b.png", &[o, o, o, o, o, i, i, i]),
+        ];
+        let project = Project::with_entries(ProjectManifest::default(), db, entries);
+        let query = mk_entry("q.png", &[o, o, o, o, o, o, o, i]).hashes;
+        let found = |threshold, exclude: Option<&str>| project.find_duplicate(&query, threshold, exclude.map(Path::new))
+            .map(|entry| entry.image_name.to_string_lossy().into_owned());
+
+        // a is 1 bit away, b is 2 bits away, a threshold right on the
+        // distance counts.
+        assert_eq!(found(1.0 / 8.0, None).as_deref(), Some("a.png"));
+        assert_eq!(found(0.9 / 8.0, None), None);
+
+        // the image being overwritten is not its own duplicate.
+        assert_eq!(found(2.0 / 8.0, Some("a.png")).as_deref(), Some("b.png"));
+        assert_eq!(found(1.0 / 8.0, Some("a.png")), None);
+
+        std::fs::remove_dir_all(&project_path).unwrap();
+    }
+
+    #[test]
+    fn test_rescan() {
+        let project_path = std::env::temp_dir().join(format!("vismatch-rescan-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&project_path).unwrap();
+
+        let save = |image_name: &str, k: u32| {
+            let image = image::GrayImage::from_fn(32, 32, |x, y| image::Luma([((x * k + y * (k + 3)) % 256) as u8]));
+            image.save(project_path.join(image_name)).unwrap();
+        };
+        for (i, image_name) in ["a.png", "b.png", "c.png", "d.png", "g.png"].iter().enumerate() {
+            save(image_name, 5 + 11 * i as u32);
+        }
+
+        let manifest = ProjectManifest::default();
+        let mut project = load_project(&project_path, &manifest, 1).unwrap();
+        assert_eq!(project.index.len(), 5);
+
+        // a is touched only, b replaced, c deleted, e added, f is no image,
+        // and d left alone.
+        let later = SystemTime::now() + std::time::Duration::from_secs(60);
+        File::options().write(true).open(project_path.join("a.png")).unwrap().set_modified(later).unwrap();
+        save("b.png", 97);
+        std::fs::remove_file(project_path.join("c.png")).unwrap();
+        save("e.png", 61);
+        std::fs::write(project_path.join("f.png"), b"not an image").unwrap();
+
+        // g is gone while planning, but back before the plan is applied.
+        let g_data = std::fs::read(project_path.join("g.png")).unwrap();
+        std::fs::remove_file(project_path.join("g.png")).unwrap();
+
+        let plan = project.plan_rescan(list_project_images(&project_path).unwrap(), false);
+        assert_eq!(plan.unchanged, 1);
+        assert_eq!(plan.gone.len(), 2);
+
+        std::fs::write(project_path.join("g.png"), g_data).unwrap();
+
+        let hashed = hash_project_images(&plan.to_hash, &manifest, 1);
+        let mut summary = project.apply_rescan(plan, hashed).unwrap();
+        summary.added.sort();
+
+        assert_eq!(summary.added, ["e.png"]);
+        assert_eq!(summary.changed, ["b.png"]);
+        assert_eq!(summary.removed, ["c.png"]);
+        assert_eq!(summary.unchanged, 2);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, "f.png");
+
+        // g is still indexed, c is not
+        assert!(project.index.get(&project_path.join("g.png")).is_some());
+        assert!(project.index.get(&project_path.join("c.png")).is_none());
+        assert_eq!(project.index.len(), 5);
+
+        // f still cannot be hashed, and g was written again meanwhile
+        let mut plan = project.plan_rescan(list_project_images(&project_path).unwrap(), false);
+        plan.to_hash.sort();
+        assert_eq!(plan.to_hash, [project_path.join("f.png"), project_path.join("g.png")]);
+        assert!(plan.gone.is_empty());
+
+        std::fs::remove_dir_all(&project_path).unwrap();
+    }
+
+    #[test]
+    fn test_put_staged_images() {
+        let project_path = std::env::temp_dir().join(format!("vismatch-stage-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&project_path).unwrap();
+
+        let image = |k: u32| DynamicImage::ImageLuma8(
+            image::GrayImage::from_fn(32, 32, |x, y| image::Luma([((x * k + y * (k + 3)) % 256) as u8])));
+        let files = || {
+            let mut names: Vec<String> = read_dir(&project_path).unwrap()
+                .map(|f| f.unwrap().file_name().to_string_lossy().into_owned())
+                .filter(|name| !name.starts_with(".index") && !name.starts_with(".project"))
+                .collect();
+            names.sort();
+            names
+        };
+
+        let manifest = ProjectManifest::default();
+        let mut project = load_project(&project_path, &manifest, 1).unwrap();
+        let a_path = project_path.join("a.png");
+
+        // a staged image is no image of the project yet.
+        let staged = stage_project_image(&a_path, &image(5), &manifest).unwrap();
+        assert_eq!(list_project_images(&project_path).unwrap(), Vec::<PathBuf>::new());
+        assert_eq!(staged.record.digest, crate::project_db::file_digest(&staged.staging_path).unwrap());
+
+        let stored = project.put_staged_images(vec![staged]).unwrap();
+        assert_eq!(stored, [Ok(())]);
+        assert_eq!(files(), ["a.png"]);
+        assert!(project.is_up_to_date(&a_path));
+        let a_data = std::fs::read(&a_path).unwrap();
+
+        // when records cannot be persisted, nothing is stored, and the
+        // replaced image is left as is.
+        let staged = vec![
+            stage_project_image(&a_path, &image(16), &manifest).unwrap(),
+            stage_project_image(&project_path.join("b.png"), &image(27), &manifest).unwrap(),
+        ];
+        assert_eq!(files().len(), 3);
+        std::fs::remove_file(project_path.join(crate::project_db::INDEX_DB_FILE_NAME)).unwrap();
+
+        assert!(project.put_staged_images(staged).is_err());
+        assert_eq!(files(), ["a.png"]);
+        assert_eq!(std::fs::read(&a_path).unwrap(), a_data);
+        assert_eq!(project.index.len(), 1);
+
+        std::fs::remove_dir_all(&project_path).unwrap();
+    }
+
+    #[test]
+    fn test_stage_project_images() {
+        let project_path = std::env::temp_dir().join(format!("vismatch-stage-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&project_path).unwrap();
+
+        let image = |k: u32| DynamicImage::ImageLuma8(
+            image::GrayImage::from_fn(32, 32, |x, y| image::Luma([((x * k + y * (k + 3)) % 256) as u8])));
+        image(5).save(project_path.join("a.png")).unwrap();
+        let a_data = std::fs::read(project_path.join("a.png")).unwrap();
+
+        let manifest = ProjectManifest::default();
+        let mut project = load_project(&project_path, &manifest, 1).unwrap();
+        let stage = |image_names: &[&str]| {
+            let image_names: Vec<String> = image_names.iter().map(|name| name.to_string()).collect();
+            stage_project_images(&project_path, &manifest, &image_names, 2, |i| match i {
+                1 => Err("not an image".into()),
+                _ => Ok(image(16 + 11 * i as u32)),
+            })
+        };
+
+        // an image which cannot be decoded fails alone.
+        let staged = stage(&["b.png", "c.png", "d.png"]);
+        assert!(staged[1].is_err());
+        let staged: Vec<StagedImage> = staged.into_iter().filter_map(Result::ok).collect();
+        assert_eq!(project.put_staged_images(staged).unwrap(), [Ok(()), Ok(())]);
+
+        let mut stored = list_project_images(&project_path).unwrap();
+        stored.sort();
+        assert_eq!(stored, ["a.png", "b.png", "d.png"].map(|name| project_path.join(name)));
+        assert_eq!(project.index.len(), 3);
+
+        // when records cannot be persisted, no staged image is left, and
+        // the images they would replace are left as is.
+        let staged: Vec<StagedImage> = stage(&["a.png", "x.png", "e.png"]).into_iter().filter_map(Result::ok).collect();
+        std::fs::remove_file(project_path.join(crate::project_db::INDEX_DB_FILE_NAME)).unwrap();
+        assert!(project.put_staged_images(staged).is_err());
+
+        let mut files: Vec<String> = read_dir(&project_path).unwrap()
+            .map(|f| f.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["a.png", "b.png", "d.png"]);
+        assert_eq!(std::fs::read(project_path.join("a.png")).unwrap(), a_data);
+        assert_eq!(project.index.len(), 3);
+
+        std::fs::remove_dir_all(&project_path).unwrap();
+    }
+
+    #[test]
+    fn test_project_tokens() {
+        let project_path = std::env::temp_dir().join(format!("vismatch-tokens-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&project_path).unwrap();
+
+        // no token file yet
+        let mut tokens = ProjectTokens::open(&project_path).unwrap();
+        assert!(tokens.is_empty());
+
+        let minted = tokens.mint(&["a.png".to_owned(), "b.png".to_owned(), "a.png".to_owned()]).unwrap();
+        assert_eq!(tokens.image_of(&minted[1]), Some("b.png"));
+
+        // every token of a gone image is dropped.
+        tokens.forget_images(&["a.png".to_owned()]).unwrap();
+        assert_eq!(tokens.len(), 1);
+
+        let mut tokens = ProjectTokens::open(&project_path).unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens.image_of(&minted[0]), None);
+        assert_eq!(tokens.image_of(&minted[1]), Some("b.png"));
+
+        // the log is compacted once most of it is dropped tokens, and a
+        // line cut by a crash is dropped.
+        for _ in 0..MIN_COMPACT_DEAD_TOKENS {
+            let minted = tokens.mint(&["c.png".to_owned()]).unwrap();
+            tokens.remove(&minted).unwrap();
+        }
+        assert!(tokens.lines < MIN_COMPACT_DEAD_TOKENS);
+
+        let mut f_handle = OpenOptions::new().append(true).open(project_path.join(TOKEN_FILE_NAME)).unwrap();
+        f_handle.write_all(b"{\"token\":\"cut").unwrap();
+        let tokens = ProjectTokens::open(&project_path).unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens.lines, 1);
+
+        std::fs::remove_dir_all(&project_path).unwrap();
+    }
+}
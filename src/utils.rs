@@ -1,4 +1,7 @@
 use std::fs::DirEntry;  // filesystem utils
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 // Some common ext for images.
 const IMAGE_EXTENSIONS: [&str; 8] = [
@@ -7,18 +10,79 @@ const IMAGE_EXTENSIONS: [&str; 8] = [
 
 /// Check if a given file is an image file
 pub fn is_image_file(file: &DirEntry) -> bool {
-    match file.path().is_file() {
-        false => false,
-        true => {
-            match file.path().extension() {
-                None => false,
-                Some(ext) => {
-                    IMAGE_EXTENSIONS.contains(
-                        &ext.to_string_lossy()
-                            .to_lowercase()
-                            .as_str())
-                },
-            }
+    file.path().is_file() && has_image_extension(&file.path())
+}
+
+/// Check if a path is named like an image, whether it exists or not
+pub fn has_image_extension(path: &Path) -> bool {
+    match path.extension() {
+        None => false,
+        Some(ext) => {
+            IMAGE_EXTENSIONS.contains(
+                &ext.to_string_lossy()
+                    .to_lowercase()
+                    .as_str())
         },
     }
-}
\ No newline at end of file
+}
+
+/// MIME type of an image, from its extension
+pub fn image_content_type(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_string_lossy().to_lowercase();
+    match ext.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "bmp" => Some("image/bmp"),
+        "ico" => Some("image/x-icon"),
+        "webp" => Some("image/webp"),
+        "tiff" => Some("image/tiff"),
+        _ => None,
+    }
+}
+
+/// Map `f` over `items` on a pool of at most `workers` threads, keeping
+/// the order of `items` in the result.
+///
+/// Workers pull the next item as soon as they are done, so slow items
+/// do not hold the others back.
+pub(crate) fn par_map<T, R, F>(items: &[T], workers: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+
+    std::thread::scope(|s| {
+        for _ in 0..workers.clamp(1, items.len().max(1)) {
+            s.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(i) else { break };
+                    let r = f(item);
+                    results.lock().unwrap()[i] = Some(r);
+                }
+            });
+        }
+    });
+
+    results.into_inner().unwrap().into_iter()
+        .map(|r| r.expect("every item is mapped once"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_par_map_keeps_order() {
+        let items: Vec<u64> = (0..100).collect();
+        for workers in [0, 1, 4, 1000] {
+            assert_eq!(par_map(&items, workers, |x| x * x), items.iter().map(|x| x * x).collect::<Vec<_>>());
+        }
+        assert!(par_map(&[] as &[u64], 4, |x| *x).is_empty());
+    }
+}
@@ -0,0 +1,317 @@
+//! Keep projects in sync with the files dropped into the project root.
+//!
+//! The project root is watched recursively. Events are collected per path,
+//! and a path is synced once no event came for it for a while, so files
+//! still being copied are not hashed half-written.
+//!
+//! - an image appearing or changing in a project folder is hashed and
+//!   indexed, unless it is indexed as it is on disk already (like uploaded
+//!   images),
+//! - an image vanishing is dropped from the index, with its deletion tokens,
+//! - a folder appearing in the project root is loaded as a new project,
+//! - a project folder vanishing drops the project,
+//! - a project which failed to load is loaded again once its manifest
+//!   changes, e.g. fixed by hand.
+//!
+//! A rename is seen as the old path vanishing, and the new one appearing.
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use notify::event::{AccessKind, AccessMode};
+
+use vismatch_svc::has_image_extension;
+use vismatch_svc::project_mgmt::{
+    MANIFEST_FILE_NAME,
+    HashProgress,
+    ProjectState,
+    hash_project_image,
+    load_projects,
+    load_or_init_project_manifest,
+    ProjectTokens,
+};
+
+use crate::{
+    ProjectHashDict,
+    TokenDict,
+    forget_image_tokens,
+    hash_workers,
+    publish_loaded_project,
+};
+
+/// A path is synced once no event came for it for this long.
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// How often settled paths are looked for.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A watched path, relative to the project root.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum WatchedPath {
+    /// A top-level folder, or the manifest in it.
+    Project(String),
+    /// An image right in a project folder, with its project name.
+    Image(String, String),
+}
+
+struct ProjectWatcher {
+    /// Project root, as project images are named in the hash dict.
+    project_root: PathBuf,
+    /// Project root as watched, event paths are under this one.
+    watched_root: PathBuf,
+    project_dict: ProjectHashDict,
+    token_dict: TokenDict,
+}
+
+/// Start watching the project root, in a thread of its own.
+pub fn spawn_project_watcher(project_root: &Path, project_dict: ProjectHashDict, token_dict: TokenDict)
+    -> notify::Result<()> {
+
+    let watched_root = project_root.canonicalize()?;
+
+    let (tx, rx) = channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&watched_root, RecursiveMode::Recursive)?;
+
+    let project_watcher = ProjectWatcher {
+        project_root: project_root.to_owned(),
+        watched_root,
+        project_dict,
+        token_dict,
+    };
+
+    std::thread::spawn(move || {
+        let _watcher = watcher; // events stop once the watcher is dropped.
+        project_watcher.run(rx);
+    });
+
+    println!("[*] watching <{}> for new images", project_root.display());
+    Ok(())
+}
+
+/// Whether an event may change what is on disk. Reading files, like we do
+/// when hashing them, does not.
+fn is_change(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+        EventKind::Access(_) => false,
+        _ => true,
+    }
+}
+
+impl ProjectWatcher {
+    fn run(self, rx: Receiver<notify::Result<Event>>) {
+        let mut pending: HashMap<WatchedPath, Instant> = HashMap::new();
+
+        loop {
+            match rx.recv_timeout(POLL_INTERVAL) {
+                Ok(Ok(event)) => {
+                    if event.need_rescan() {
+                        println!("[!] project watcher missed some events, rescan projects to catch up");
+                    }
+                    if is_change(&event.kind) {
+                        for path in event.paths.iter() {
+                            if let Some(watched) = self.classify(path) {
+                                pending.insert(watched, Instant::now());
+                            }
+                        }
+                    }
+                },
+                Ok(Err(err)) => println!("[!] project watcher error: {}", err),
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            let settled: Vec<WatchedPath> = pending.iter()
+                .filter(|(_, t)| t.elapsed() >= SETTLE_TIME)
+                .map(|(watched, _)| watched.clone())
+                .collect();
+
+            for watched in settled {
+                let done = match &watched {
+                    WatchedPath::Project(project_name) => self.sync_project(project_name),
+                    WatchedPath::Image(project_name, image_name) => self.sync_image(project_name, image_name),
+                };
+
+                // paths of projects still loading are tried again later.
+                match done {
+                    true => pending.remove(&watched),
+                    false => pending.insert(watched, Instant::now()),
+                };
+            }
+        }
+
+        println!("[!] project watcher stopped");
+    }
+
+    /// Tell what an event path is, `None` for paths we do not care about.
+    fn classify(&self, path: &Path) -> Option<WatchedPath> {
+        let names: Vec<String> = path.strip_prefix(&self.watched_root).ok()?
+            .components()
+            .map(|c| match c {
+                Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect::<Option<_>>()?;
+
+        match names.as_slice() {
+            [project_name] => Some(WatchedPath::Project(project_name.clone())),
+            [project_name, file_name] if file_name == MANIFEST_FILE_NAME =>
+                Some(WatchedPath::Project(project_name.clone())),
+            [project_name, image_name] if has_image_extension(Path::new(image_name)) =>
+                Some(WatchedPath::Image(project_name.clone(), image_name.clone())),
+            _ => None,
+        }
+    }
+
+    /// Load a new project folder, or one which failed to load before, and
+    /// drop a project whose folder is gone.
+    fn sync_project(&self, project_name: &str) -> bool {
+        let project_path = self.project_root.join(project_name);
+        let mut project_dict_wlock = self.project_dict.blocking_write();
+
+        match (project_path.is_dir(), project_dict_wlock.get(project_name)) {
+            (true, None | Some(ProjectState::Failed(_))) => {
+                match project_dict_wlock.contains_key(project_name) {
+                    true => println!("[*] loading failed project <{}> again", project_name),
+                    false => println!("[*] found new project folder <{}>", project_name),
+                }
+
+                let manifest = match load_or_init_project_manifest(&project_path) {
+                    Ok(manifest) => manifest,
+                    Err(err) => {
+                        println!("[!] cannot load project <{}>: {}", project_name, err);
+                        project_dict_wlock.insert(project_name.to_owned(), ProjectState::Failed(err.to_string()));
+                        return true;
+                    },
+                };
+
+                let progress = Arc::new(HashProgress::new());
+                project_dict_wlock.insert(project_name.to_owned(), ProjectState::Loading(Arc::clone(&progress)));
+
+                // a folder moved in may bring its deletion tokens.
+                match ProjectTokens::open(&project_path) {
+                    Ok(tokens) => { self.token_dict.blocking_write().insert(project_name.to_owned(), tokens); },
+                    Err(err) => println!("[!] cannot load tokens of project <{}>: {}", project_name, err),
+                }
+
+                drop(project_dict_wlock);
+
+                let project_dict = Arc::clone(&self.project_dict);
+                let project_name = project_name.to_owned();
+                std::thread::spawn(move || {
+                    load_projects(&[(project_path, manifest)], hash_workers(), &[Arc::clone(&progress)], |_, project| {
+                        publish_loaded_project(&project_dict, &project_name, &progress, project);
+                    });
+                });
+            },
+            (false, Some(_)) => {
+                println!("[*] project folder <{}> is gone, dropping the project", project_name);
+                project_dict_wlock.remove(project_name);
+                self.token_dict.blocking_write().remove(project_name);
+            },
+            _ => {},
+        }
+
+        true
+    }
+
+    /// Index an image of a project as it is on disk now, or drop it if it
+    /// is gone. Returns `false` if the project is not ready yet.
+    fn sync_image(&self, project_name: &str, image_name: &str) -> bool {
+        let project_path = self.project_root.join(project_name);
+        let image_path = project_path.join(image_name);
+        let exists = image_path.is_file();
+
+        // images of a removed project folder go with their project.
+        if !project_path.is_dir() {
+            return true;
+        }
+
+        // images are hashed without holding the lock.
+        let manifest = match self.project_dict.blocking_read().get(project_name) {
+            Some(ProjectState::Loading(_)) => return false,
+            Some(ProjectState::Ready(project)) => {
+                if exists && project.is_up_to_date(&image_path) {
+                    return true;
+                }
+                project.manifest.clone()
+            },
+            // unknown projects are picked up by their folder event.
+            _ => return true,
+        };
+
+        let hashed = match exists {
+            false => None,
+            true => match hash_project_image(&image_path, &manifest) {
+                Ok(hashed) => Some(hashed),
+                Err(err) => {
+                    println!("[!] cannot index <{}>: {}", image_path.display(), err);
+                    return true;
+                },
+            },
+        };
+
+        let mut project_dict_wlock = self.project_dict.blocking_write();
+        let Some(project) = project_dict_wlock.get_mut(project_name).and_then(ProjectState::ready_mut) else {
+            return true;
+        };
+
+        match hashed {
+            Some((entry, record)) => match project.put_image(entry, record) {
+                Ok(_) => println!("[*] indexed <{}>", image_path.display()),
+                Err(err) => println!("[!] cannot index <{}>: {}", image_path.display(), err),
+            },
+            None => match project.remove_image(&image_path) {
+                Ok(true) => {
+                    println!("[*] <{}> is gone, dropped from index", image_path.display());
+                    let forgotten = forget_image_tokens(&mut self.token_dict.blocking_write(),
+                        &project_path, project_name, &[image_name.to_owned()]);
+                    if let Err(err) = forgotten {
+                        println!("[!] cannot persist deletion tokens of project <{}>: {}", project_name, err);
+                    }
+                },
+                Ok(false) => {},
+                Err(err) => println!("[!] cannot drop <{}>: {}", image_path.display(), err),
+            },
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::RwLock;
+
+    #[test]
+    fn test_classify() {
+        let project_watcher = ProjectWatcher {
+            project_root: PathBuf::from("projects"),
+            watched_root: PathBuf::from("/srv/projects"),
+            project_dict: Arc::new(RwLock::new(HashMap::new())),
+            token_dict: Arc::new(RwLock::new(HashMap::new())),
+        };
+        let classify = |path: &str| project_watcher.classify(Path::new(path));
+
+        // top-level folders, and their manifest
+        assert_eq!(classify("/srv/projects/cars"), Some(WatchedPath::Project("cars".to_owned())));
+        assert_eq!(classify("/srv/projects/cars/.project.json"), Some(WatchedPath::Project("cars".to_owned())));
+
+        // images right in a project folder
+        assert_eq!(classify("/srv/projects/cars/a.PNG"),
+            Some(WatchedPath::Image("cars".to_owned(), "a.PNG".to_owned())));
+
+        // nested paths, other files, and paths outside the root
+        assert_eq!(classify("/srv/projects/cars/old/a.png"), None);
+        assert_eq!(classify("/srv/projects/cars/.index.vmdb"), None);
+        assert_eq!(classify("/srv/projects/cars/notes.txt"), None);
+        assert_eq!(classify("/srv/projects"), None);
+        assert_eq!(classify("/srv/other/cars/a.png"), None);
+    }
+}